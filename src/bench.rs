@@ -0,0 +1,173 @@
+use crate::chip8::Interpreter;
+use crate::config::{headless_settings, Settings};
+use clap::Args;
+#[cfg(feature = "count-allocations")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// With the count-allocations feature, wrap the system allocator so the benchmark can
+// report how many allocations happen inside the interpreter loop. The counter is a
+// single relaxed atomic, but it is paid for on every allocation of every run, so it is
+// left out of regular builds.
+#[cfg(feature = "count-allocations")]
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "count-allocations")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    #[arg(long)]
+    pub binary: String,
+    /// Number of cycles to run for.
+    #[arg(long, conflicts_with = "seconds")]
+    cycles: Option<u64>,
+    /// Number of (wall-clock) seconds to run for.
+    #[arg(long, value_parser = parse_seconds)]
+    seconds: Option<f64>,
+    /// Time every instruction and print a per-opcode cost breakdown.
+    #[arg(long)]
+    per_opcode: bool,
+    /// Config file with global and per-ROM settings.
+    #[arg(long, default_value = crate::config::DEFAULT_CONFIG_PATH)]
+    config: String,
+    /// ROM database to detect settings from, instead of the bundled one.
+    #[arg(long)]
+    database: Option<String>,
+    #[command(flatten)]
+    settings: Settings,
+}
+
+const DEFAULT_BENCH_CYCLES: u64 = 10_000_000;
+// Only check the wall clock every so often when running for a fixed time, so that
+// calls to Instant::now() do not dominate the measurement.
+const TIME_CHECK_INTERVAL: u64 = 4096;
+
+#[derive(Default)]
+struct OpcodeCost {
+    count: u64,
+    total: Duration,
+}
+
+pub fn run(args: &BenchArgs) -> std::io::Result<()> {
+    let rom = std::fs::read(&args.binary)?;
+    let settings = headless_settings(
+        &args.binary,
+        &rom,
+        &args.config,
+        args.database.as_deref(),
+        &args.settings,
+    )?;
+    let mut chip8 = Interpreter::new()
+        .with_platform(settings.platform.unwrap_or_default())
+        .with_quirks(settings.quirks.unwrap_or_default())
+        .with_hz(settings.hz())
+        .load_rom(&rom)?;
+    let limit = args.seconds.map(Duration::from_secs_f64);
+    let max_cycles = match limit {
+        Some(_) => u64::MAX,
+        None => args.cycles.unwrap_or(DEFAULT_BENCH_CYCLES),
+    };
+    let mut costs: HashMap<&'static str, OpcodeCost> = HashMap::new();
+
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut cycles = 0;
+    while cycles < max_cycles {
+        if args.per_opcode {
+            let insn_start = Instant::now();
+            let insn = chip8.cycle();
+            let cost = costs.entry(insn.mnemonic()).or_default();
+            cost.count += 1;
+            cost.total += insn_start.elapsed();
+        } else {
+            chip8.cycle();
+        }
+        cycles += 1;
+        if let Some(limit) = limit {
+            if cycles.is_multiple_of(TIME_CHECK_INTERVAL) && start.elapsed() >= limit {
+                break;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+
+    let cycles_per_frame = (chip8.hz() / 60) as u64;
+    let frames = (cycles / cycles_per_frame).max(1);
+    println!("Binary:          {}", args.binary);
+    println!("Platform:        {}", chip8.platform().name());
+    println!("Cycles:          {}", cycles);
+    println!("Elapsed:         {:.3?}", elapsed);
+    println!(
+        "IPS:             {:.0}",
+        cycles as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Time per frame:  {:.3?} ({} cycles/frame, budget {:.3?})",
+        Duration::from_secs_f64(elapsed.as_secs_f64() / frames as f64),
+        cycles_per_frame,
+        Duration::from_secs_f64(1.0 / 60.0)
+    );
+    if cfg!(feature = "count-allocations") {
+        println!(
+            "Allocations:     {} ({:.3} per frame)",
+            allocations,
+            allocations as f64 / frames as f64
+        );
+    } else {
+        println!("Allocations:     not counted (build with --features count-allocations)");
+    }
+
+    if args.per_opcode {
+        let mut costs: Vec<_> = costs.into_iter().collect();
+        costs.sort_by_key(|(_, cost)| std::cmp::Reverse(cost.total));
+        println!();
+        println!(
+            "{:<20} {:>12} {:>12} {:>10}",
+            "Opcode", "Count", "Total", "Avg (ns)"
+        );
+        for (name, cost) in costs {
+            println!(
+                "{:<20} {:>12} {:>12.3?} {:>10.1}",
+                name,
+                cost.count,
+                cost.total,
+                cost.total.as_nanos() as f64 / cost.count as f64
+            );
+        }
+        println!("Note: timings include the overhead of reading the clock per instruction.");
+    }
+    Ok(())
+}
+
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+        _ => Err(format!(
+            "Invalid duration {s}, expected a positive number of seconds"
+        )),
+    }
+}
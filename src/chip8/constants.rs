@@ -2,9 +2,12 @@ use winit::event::VirtualKeyCode;
 
 pub const CHIP8_WIDTH: usize = 64;
 pub const CHIP8_HEIGHT: usize = 32;
-pub const CHIP8_WIN_SCALING_HEIGHT: usize = 10;
-pub const CHIP8_WIN_SCALING_WIDTH: usize = 10;
+pub const SCHIP_HIRES_WIDTH: usize = 128;
+pub const SCHIP_HIRES_HEIGHT: usize = 64;
+pub const CHIP8_WIN_SCALING: u32 = 10;
 pub const CHIP8_MEMORY_SIZE: usize = 4 * 1024; // 4kb
+pub const XOCHIP_MEMORY_SIZE: usize = 64 * 1024;
+pub const CHIP8_PROGRAM_START: usize = 0x200;
 pub const CHIP8_SPEED_HZ: u32 = 1000;
 pub const IPS_MEASURE_CYCLE: u32 = CHIP8_SPEED_HZ;
 pub const CHIP8_BEEP_FREQUENCY: f32 = 440.0;
@@ -45,4 +48,4 @@ pub const CHIP8_FONT: [u8; 80] = [
     0xE0, 0x90, 0x90, 0x90, 0xE0, // E,
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // F,
     0xF0, 0x80, 0xF0, 0x80, 0x80,
-];
\ No newline at end of file
+];
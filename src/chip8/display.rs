@@ -0,0 +1,137 @@
+// Display with the two XO-CHIP bitplanes, stored as one bit per pixel and plane. Each
+// row is packed into a u128 with pixel x at bit (127 - x), so a whole sprite line can
+// be drawn, and checked for collisions, with a handful of shifts and logic operations.
+// Displays up to 128 pixels wide are supported. Programs only draw to the second plane
+// after selecting it with FN01, so other programs are monochrome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Display {
+    width: usize,
+    height: usize,
+    planes: [Vec<u128>; PLANES],
+}
+
+const ROW_BITS: usize = u128::BITS as usize;
+pub const PLANES: usize = 2;
+
+impl Display {
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(width <= ROW_BITS, "Display too wide");
+        Display {
+            width,
+            height,
+            planes: [vec![0; height], vec![0; height]],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Packed pixels of a plane, one row per line.
+    pub fn rows(&self, plane: usize) -> &[u128] {
+        &self.planes[plane]
+    }
+
+    // Clear the planes set in the mask (bit 0 for the first plane).
+    pub fn clear(&mut self, planes: u8) {
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) != 0 {
+                rows.fill(0);
+            }
+        }
+    }
+
+    // The display scaled up by an integer factor, e.g. to show a lores frame at the SCHIP
+    // hires size.
+    pub fn scaled(&self, factor: usize) -> Display {
+        let mut scaled = Display::new(self.width * factor, self.height * factor);
+        for (plane, rows) in self.planes.iter().enumerate() {
+            for (y, &row) in rows.iter().enumerate() {
+                let mut wide = 0;
+                for x in (0..self.width).filter(|x| (row >> (ROW_BITS - 1 - x)) & 0x1 == 1) {
+                    for i in 0..factor {
+                        wide |= 1 << (ROW_BITS - 1 - (x * factor + i));
+                    }
+                }
+                scaled.planes[plane][y * factor..(y + 1) * factor].fill(wide);
+            }
+        }
+        scaled
+    }
+
+    // Whether the pixel is set in any plane.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.color(x, y) != 0
+    }
+
+    // Palette index of a pixel: bit 0 is set by the first plane, bit 1 by the second.
+    pub fn color(&self, x: usize, y: usize) -> usize {
+        let bit = |rows: &Vec<u128>| ((rows[y] >> (ROW_BITS - 1 - x)) & 0x1) as usize;
+        bit(&self.planes[0]) | (bit(&self.planes[1]) << 1)
+    }
+
+    // XOR an 8 pixel wide sprite onto the display at (x, y), either wrapping around the
+    // edges or clipped at them. The position itself always wraps. Returns true if any
+    // pixel was turned off (i.e. there was a collision).
+    pub fn draw_sprite(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        wrap: bool,
+    ) -> bool {
+        let lines = sprite.iter().map(|&line| line as u128);
+        self.draw(plane, x, y, 8, lines, wrap)
+    }
+
+    // Same as draw_sprite, for 16x16 SCHIP sprites stored as two bytes per line.
+    pub fn draw_wide_sprite(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        sprite: &[u8],
+        wrap: bool,
+    ) -> bool {
+        let lines = sprite
+            .chunks_exact(2)
+            .map(|line| u16::from_be_bytes([line[0], line[1]]) as u128);
+        self.draw(plane, x, y, 16, lines, wrap)
+    }
+
+    fn draw(
+        &mut self,
+        plane: usize,
+        x: usize,
+        y: usize,
+        sprite_width: usize,
+        lines: impl Iterator<Item = u128>,
+        wrap: bool,
+    ) -> bool {
+        let x = x % self.width;
+        let y = y % self.height;
+        let mask = !0u128 << (ROW_BITS - self.width);
+        let mut collision = false;
+        for (j, line) in lines.enumerate() {
+            if !wrap && y + j >= self.height {
+                break;
+            }
+            let bits = line << (ROW_BITS - sprite_width);
+            let mut shifted = bits >> x;
+            if wrap && x + sprite_width > self.width {
+                // Wrap the part that went past the right edge back to the left.
+                shifted |= bits << (self.width - x);
+            }
+            let shifted = shifted & mask;
+            let row = &mut self.planes[plane][(y + j) % self.height];
+            collision |= *row & shifted != 0;
+            *row ^= shifted;
+        }
+        collision
+    }
+}
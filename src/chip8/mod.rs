@@ -1,16 +1,24 @@
-use log::{debug, trace};
-use pixels::Pixels;
-use rand::Rng;
-use rodio::source::SineWave;
-use rodio::{OutputStream, Sink};
-use std::collections::HashMap;
+use log::{debug, error, trace};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
 use std::time::Instant;
-use winit::event::VirtualKeyCode;
 
 pub mod constants;
 use constants::*;
+mod display;
+pub use display::Display;
+use display::PLANES;
+mod platform;
+pub use platform::Platform;
+mod quirks;
+pub use quirks::Quirks;
+pub mod rom;
+use rom::RomError;
 mod sleeper;
+pub mod sound;
 use sleeper::Sleeper;
+use sound::{Beeper, Pattern, Tone};
 
 fn nibbles(insn: u16) -> (u8, u8, u8, u8) {
     (
@@ -29,27 +37,92 @@ pub struct Interpreter {
     pc: u16,
     sp: u8,
     stack: [u16; 16],
-    pixels: Pixels,
-    memory: [u8; CHIP8_MEMORY_SIZE],
-    pub keyboard_map: HashMap<VirtualKeyCode, u32>,
-    pub keypad: [bool; 16],
-    pub key_pressed: Option<u32>,
+    display: Display,
+    // XO-CHIP planes selected for drawing and clearing, as a bit mask.
+    planes: u8,
+    platform: Platform,
+    quirks: Quirks,
+    memory: Vec<u8>,
+    // Predecoded instructions indexed by address. Entries are filled lazily on first
+    // execution and invalidated whenever the program writes to memory they cover.
+    decode_cache: Vec<Option<Instruction>>,
+    keypad: [bool; 16],
+    // Key events not yet seen by the program, applied at the start of the next cycle.
+    // Each key changes at most once per frame, and further events wait for the next
+    // frame, so that a press and release in quick succession do not cancel out before
+    // the program polls the keypad.
+    key_events: VecDeque<KeyEvent>,
+    // Keys changed during the current frame, as a bit mask.
+    keys_changed: u16,
+    key_wait: KeyWait,
     pub cycle_count: u32,
+    frame_count: u64,
+    last_draw: Option<DrawInfo>,
+    // Set on every timer tick, and cleared by drawing with the vblank quirk.
+    vblank: bool,
+    rng: StdRng,
     hz: u32,
     timer: Instant,
+    throttle: bool,
     sleeper: Sleeper,
-    _sound_stream: OutputStream,
-    sound_sink: Sink,
+    beeper: Option<Beeper>,
+    // XO-CHIP audio, only set once a pattern has been loaded.
+    pattern: Option<Pattern>,
+    pitch: u8,
+}
+
+// Snapshot of the registers, for debugging displays.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+// Where the last Draw instruction put its sprite, for debugging displays.
+#[derive(Debug, Clone, Copy)]
+pub struct DrawInfo {
+    // Sprite address and size.
+    pub addr: u16,
+    pub width: usize,
+    pub height: usize,
+    // Top left corner on the display, after wrapping.
+    pub x: usize,
+    pub y: usize,
+    pub collision: bool,
+}
+
+// A change to the state of the keypad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Press(u8),
+    Release(u8),
+}
+
+// Progress of a WaitKeypress (FX0A) instruction. As on the COSMAC VIP, it waits for a
+// key to go down, and then completes once that key is released. Keys that are already
+// down when it starts do not count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyWait {
+    Idle,
+    Press,
+    Release(u8),
+    Done(u8),
 }
 
 type Reg = u8;
 type Addr = u16;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     Nop,
     Clear,
     Return,
+    LoRes,
+    HiRes,
     Jump(Addr),
     Call(Addr),
     LoadI(Addr),
@@ -65,8 +138,11 @@ pub enum Instruction {
     LoadFromDelayTimer(Reg),
     LoadDelayTimer(Reg),
     LoadSoundTimer(Reg),
-    Shl(Reg),
-    Shr(Reg),
+    LoadPattern,
+    SetPitch(Reg),
+    Planes(u8),
+    Shl(Reg, Reg),
+    Shr(Reg, Reg),
     SkipEq(Reg, Reg),
     SkipEqIm(Reg, u8),
     SkipNe(Reg, Reg),
@@ -86,63 +162,360 @@ pub enum Instruction {
 
 use Instruction::*;
 
-impl Interpreter {
-    pub fn new(pixels: Pixels) -> Interpreter {
-        let keyboard_map = HashMap::from(CHIP8_KEYBOARD_MAP);
-        let (stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
-        let source = SineWave::new(CHIP8_BEEP_FREQUENCY);
-        sink.append(source);
-        sink.pause();
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Nop => "Nop",
+            Clear => "Clear",
+            Return => "Return",
+            LoRes => "LoRes",
+            HiRes => "HiRes",
+            Jump(_) => "Jump",
+            Call(_) => "Call",
+            LoadI(_) => "LoadI",
+            JumpOff(_) => "JumpOff",
+            AddI(_) => "AddI",
+            LoadRegs(_) => "LoadRegs",
+            StoreRegs(_) => "StoreRegs",
+            StoreBcd(_) => "StoreBcd",
+            SetSpriteAddr(_) => "SetSpriteAddr",
+            SkipPressed(_) => "SkipPressed",
+            SkipNotPressed(_) => "SkipNotPressed",
+            WaitKeypress(_) => "WaitKeypress",
+            LoadFromDelayTimer(_) => "LoadFromDelayTimer",
+            LoadDelayTimer(_) => "LoadDelayTimer",
+            LoadSoundTimer(_) => "LoadSoundTimer",
+            LoadPattern => "LoadPattern",
+            SetPitch(_) => "SetPitch",
+            Planes(_) => "Planes",
+            Shl(_, _) => "Shl",
+            Shr(_, _) => "Shr",
+            SkipEq(_, _) => "SkipEq",
+            SkipEqIm(_, _) => "SkipEqIm",
+            SkipNe(_, _) => "SkipNe",
+            SkipNeIm(_, _) => "SkipNeIm",
+            LoadIm(_, _) => "LoadIm",
+            AddIm(_, _) => "AddIm",
+            Move(_, _) => "Move",
+            Or(_, _) => "Or",
+            And(_, _) => "And",
+            Xor(_, _) => "Xor",
+            Add(_, _) => "Add",
+            Sub(_, _) => "Sub",
+            SubN(_, _) => "SubN",
+            Rnd(_, _) => "Rnd",
+            Draw(_, _, _) => "Draw",
+        }
+    }
+}
 
+pub fn decode(instruction: u16) -> Instruction {
+    let decoded_insn = match nibbles(instruction) {
+        (0, 0, 0xE, 0) => Clear,
+        (0, 0, 0xE, 0xE) => Return,
+        (0, 0, 0xF, 0xE) => LoRes,
+        (0, 0, 0xF, 0xF) => HiRes,
+        (1, _, _, _) => {
+            let nnn = instruction & 0xFFF;
+            Jump(nnn)
+        }
+        (2, _, _, _) => {
+            let nnn = instruction & 0xFFF;
+            Call(nnn)
+        }
+        (3, x, _, _) => {
+            let kk: u8 = (instruction & 0xFF) as u8;
+            SkipEqIm(x, kk)
+        }
+        (4, x, _, _) => {
+            let kk: u8 = (instruction & 0xFF) as u8;
+            SkipNeIm(x, kk)
+        }
+        (5, x, y, 0) => SkipEq(x, y),
+        (6, x, _, _) => {
+            let kk: u8 = (instruction & 0xFF) as u8;
+            LoadIm(x, kk)
+        }
+        (7, x, _, _) => {
+            let kk: u8 = (instruction & 0xFF) as u8;
+            AddIm(x, kk)
+        }
+        (8, x, y, 0) => Move(x, y),
+        (8, x, y, 1) => Or(x, y),
+        (8, x, y, 2) => And(x, y),
+        (8, x, y, 3) => Xor(x, y),
+        (8, x, y, 4) => Add(x, y),
+        (8, x, y, 5) => Sub(x, y),
+        (8, x, y, 6) => Shr(x, y),
+        (8, x, y, 7) => SubN(x, y),
+        (8, x, y, 0xE) => Shl(x, y),
+        (9, x, y, 0) => SkipNe(x, y),
+        (0xA, _, _, _) => {
+            let nnn = instruction & 0xFFF;
+            LoadI(nnn)
+        }
+        (0xB, _, _, _) => {
+            let nnn = instruction & 0xFFF;
+            JumpOff(nnn)
+        }
+        (0xC, x, _, _) => {
+            let kk: u8 = (instruction & 0xFF) as u8;
+            Rnd(x, kk)
+        }
+        (0xD, x, y, n) => Draw(x, y, n),
+        (0xE, x, 9, 0xE) => SkipPressed(x),
+        (0xE, x, 0xA, 1) => SkipNotPressed(x),
+        (0xF, x, 0, 7) => LoadFromDelayTimer(x),
+        (0xF, x, 0, 0xA) => WaitKeypress(x),
+        (0xF, x, 1, 5) => LoadDelayTimer(x),
+        (0xF, x, 1, 8) => LoadSoundTimer(x),
+        (0xF, 0, 0, 2) => LoadPattern,
+        (0xF, x, 3, 0xA) => SetPitch(x),
+        (0xF, n, 0, 1) => Planes(n & 0x3),
+        (0xF, x, 1, 0xE) => AddI(x),
+        (0xF, x, 2, 9) => SetSpriteAddr(x),
+        (0xF, x, 3, 3) => StoreBcd(x),
+        (0xF, x, 5, 5) => StoreRegs(x),
+        (0xF, x, 6, 5) => LoadRegs(x),
+        (_, _, _, _) => Nop,
+    };
+    trace!("Decoded instruction {:?}", decoded_insn);
+    decoded_insn
+}
+
+impl Interpreter {
+    // Creates a silent, unthrottled interpreter. Use the builder methods below to
+    // attach sound output and throttling.
+    pub fn new() -> Interpreter {
         let mut chip = Interpreter {
             v: [0; 16],
             i: 0,
             delay_timer: 0,
             sound_timer: 0,
-            pc: 0x200,
+            pc: CHIP8_PROGRAM_START as u16,
             sp: 0,
             stack: [0; 16],
-            memory: [0; CHIP8_MEMORY_SIZE],
-            pixels,
-            keyboard_map,
+            platform: Platform::default(),
+            quirks: Quirks::default(),
+            memory: vec![0; CHIP8_MEMORY_SIZE],
+            decode_cache: vec![None; CHIP8_MEMORY_SIZE],
+            display: Display::new(CHIP8_WIDTH, CHIP8_HEIGHT),
+            planes: 1,
             keypad: [false; 16],
-            key_pressed: None,
+            key_events: VecDeque::new(),
+            keys_changed: 0,
+            key_wait: KeyWait::Idle,
             cycle_count: 0,
+            frame_count: 0,
+            last_draw: None,
+            vblank: false,
+            rng: StdRng::from_entropy(),
             hz: CHIP8_SPEED_HZ,
             timer: Instant::now(),
-            sleeper: Sleeper::new().with_frequency(CHIP8_SPEED_HZ),
-            sound_sink: sink,
-            _sound_stream: stream,
+            throttle: false,
+            sleeper: Sleeper::new()
+                .with_frequency(CHIP8_SPEED_HZ)
+                .with_batch(CHIP8_SPEED_HZ / 60),
+            beeper: None,
+            pattern: None,
+            pitch: Pattern::DEFAULT_PITCH,
         };
         chip.load_fonts();
         chip
     }
 
+    // Size the memory for the platform. Clears the memory, so this must come before
+    // loading a ROM.
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self.memory = vec![0; platform.memory_size()];
+        self.decode_cache = vec![None; platform.memory_size()];
+        self.load_fonts();
+        self
+    }
+
+    // Play the beeper on the default audio output. Without one, the interpreter stays
+    // silent.
+    pub fn with_sound(mut self, tone: Tone) -> Self {
+        self.beeper = Beeper::new(tone)
+            .map_err(|e| error!("Could not open audio output: {}", e))
+            .ok();
+        self
+    }
+
+    // Seed the random number generator, to make runs reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    // Run at hz cycles per second, rather than CHIP8_SPEED_HZ. Timers tick every
+    // hz / 60 cycles, so this sets the number of cycles per frame.
+    pub fn with_hz(mut self, hz: u32) -> Self {
+        self.hz = hz.max(60);
+        self.sleeper = self.new_sleeper(1);
+        self
+    }
+
+    pub fn with_throttling(mut self, throttle: bool) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn hz(&self) -> u32 {
+        self.hz
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Run at a multiple of the normal speed when throttled. Timers keep ticking every
+    // hz / 60 cycles, so the whole program is sped up.
+    pub fn set_speed(&mut self, factor: u32) {
+        self.sleeper = self.new_sleeper(factor);
+    }
+
+    // Throttling waits once per frame's worth of cycles, rather than after every
+    // instruction, so that it can sleep instead of spinning.
+    fn new_sleeper(&self, factor: u32) -> Sleeper {
+        Sleeper::new()
+            .with_frequency(self.hz.saturating_mul(factor))
+            .with_batch(self.hz / 60)
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        }
+    }
+
     fn load_fonts(&mut self) {
         let fonts = CHIP8_FONT;
         self.memory[0..fonts.len()].copy_from_slice(&fonts);
     }
 
-    pub fn draw(&self) {
-        self.pixels.render().expect("Error while rendering pixels");
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn last_draw(&self) -> Option<DrawInfo> {
+        self.last_draw
+    }
+
+    pub fn is_beeping(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    pub fn pattern(&self) -> Option<Pattern> {
+        self.pattern
+    }
+
+    // Queue a key event. Keys are numbered 0x0-0xF, and others are ignored.
+    pub fn key_event(&mut self, event: KeyEvent) {
+        self.key_events.push_back(event);
     }
 
-    pub fn resize_window(&mut self, width: u32, height: u32) {
-        self.pixels
-            .resize_surface(width, height)
-            .expect("Could not resize window");
+    pub fn press_key(&mut self, key: u8) {
+        self.key_event(KeyEvent::Press(key));
     }
 
-    pub fn load_binary(mut self, binary: &str) -> std::io::Result<Self> {
-        debug!("Loading binary {binary}.");
-        let buffer = std::fs::read(binary)?;
-        let start_address = 0x200;
-        self.memory[start_address..(start_address + buffer.len())].copy_from_slice(&buffer[..]);
+    pub fn release_key(&mut self, key: u8) {
+        self.key_event(KeyEvent::Release(key));
+    }
+
+    pub fn keypad(&self) -> &[bool; 16] {
+        &self.keypad
+    }
+
+    fn apply_key_events(&mut self) {
+        while let Some(&event) = self.key_events.front() {
+            let (key, pressed) = match event {
+                KeyEvent::Press(key) => (key, true),
+                KeyEvent::Release(key) => (key, false),
+            };
+            if key > 0xF {
+                self.key_events.pop_front();
+                continue;
+            }
+            if self.keys_changed & (1 << key) != 0 {
+                // Keep this and later events in order for the next frame.
+                break;
+            }
+            self.key_events.pop_front();
+            self.keys_changed |= 1 << key;
+            debug!("Updating keypad {} to {}", key, pressed);
+            self.keypad[key as usize] = pressed;
+            self.key_wait = match (self.key_wait, event) {
+                (KeyWait::Press, KeyEvent::Press(key)) => KeyWait::Release(key),
+                (KeyWait::Release(waiting), KeyEvent::Release(key)) if waiting == key => {
+                    KeyWait::Done(key)
+                }
+                (key_wait, _) => key_wait,
+            };
+        }
+    }
+
+    // SHA-1 hash of the machine state, to check that two runs ended up the same.
+    pub fn state_hash(&self) -> String {
+        let mut hasher = sha1_smol::Sha1::new();
+        hasher.update(&self.v);
+        hasher.update(&self.i.to_be_bytes());
+        hasher.update(&self.pc.to_be_bytes());
+        hasher.update(&[self.sp, self.delay_timer, self.sound_timer]);
+        for addr in self.stack {
+            hasher.update(&addr.to_be_bytes());
+        }
+        hasher.update(&self.memory);
+        hasher.update(&(self.display.width() as u32).to_be_bytes());
+        for row in self.display.rows(0) {
+            hasher.update(&row.to_be_bytes());
+        }
+        // The second plane only counts once used, so that monochrome programs hash the
+        // same as before it existed.
+        let second_plane = self.display.rows(1);
+        if self.planes != 1 || second_plane.iter().any(|&row| row != 0) {
+            hasher.update(&[self.planes]);
+            for row in second_plane {
+                hasher.update(&row.to_be_bytes());
+            }
+        }
+        hasher.digest().to_string()
+    }
+
+    // Number of 60Hz frames (timer ticks) emulated so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Load a program, checking that it fits in memory and looks like a CHIP-8 program.
+    pub fn load_rom(mut self, rom: &[u8]) -> Result<Self, RomError> {
+        rom::validate(rom, self.platform)?;
+        let start_address = CHIP8_PROGRAM_START;
+        self.memory[start_address..(start_address + rom.len())].copy_from_slice(rom);
+        self.decode_cache.fill(None);
         Ok(self)
     }
 
-    fn fetch(&mut self) -> u16 {
+    fn fetch(&self) -> u16 {
         let pc = self.pc as usize;
         let instruction: u16 = u16::from_be_bytes([self.memory[pc], self.memory[pc + 1]]);
         trace!(
@@ -150,91 +523,42 @@ impl Interpreter {
             instruction,
             self.pc,
         );
-        self.pc += 2;
         instruction
     }
 
-    fn decode(&self, instruction: u16) -> Instruction {
-        let decoded_insn = match nibbles(instruction) {
-            (0, 0, 0xE, 0) => Clear,
-            (0, 0, 0xE, 0xE) => Return,
-            (1, _, _, _) => {
-                let nnn = instruction & 0xFFF;
-                Jump(nnn)
-            }
-            (2, _, _, _) => {
-                let nnn = instruction & 0xFFF;
-                Call(nnn)
-            }
-            (3, x, _, _) => {
-                let kk: u8 = (instruction & 0xFF) as u8;
-                SkipEqIm(x, kk)
-            }
-            (4, x, _, _) => {
-                let kk: u8 = (instruction & 0xFF) as u8;
-                SkipNeIm(x, kk)
-            }
-            (5, x, y, 0) => SkipEq(x, y),
-            (6, x, _, _) => {
-                let kk: u8 = (instruction & 0xFF) as u8;
-                LoadIm(x, kk)
-            }
-            (7, x, _, _) => {
-                let kk: u8 = (instruction & 0xFF) as u8;
-                AddIm(x, kk)
-            }
-            (8, x, y, 0) => Move(x, y),
-            (8, x, y, 1) => Or(x, y),
-            (8, x, y, 2) => And(x, y),
-            (8, x, y, 3) => Xor(x, y),
-            (8, x, y, 4) => Add(x, y),
-            (8, x, y, 5) => Sub(x, y),
-            (8, x, _, 6) => Shr(x),
-            (8, x, y, 7) => SubN(x, y),
-            (8, x, _, 0xE) => Shl(x),
-            (9, x, y, 0) => SkipNe(x, y),
-            (0xA, _, _, _) => {
-                let nnn = instruction & 0xFFF;
-                LoadI(nnn)
-            }
-            (0xB, _, _, _) => {
-                let nnn = instruction & 0xFFF;
-                JumpOff(nnn)
-            }
-            (0xC, x, _, _) => {
-                let kk: u8 = (instruction & 0xFF) as u8;
-                Rnd(x, kk)
-            }
-            (0xD, x, y, n) => Draw(x, y, n),
-            (0xE, x, 9, 0xE) => SkipPressed(x),
-            (0xE, x, 0xA, 1) => SkipNotPressed(x),
-            (0xF, x, 0, 7) => LoadFromDelayTimer(x),
-            (0xF, x, 0, 0xA) => WaitKeypress(x),
-            (0xF, x, 1, 5) => LoadDelayTimer(x),
-            (0xF, x, 1, 8) => LoadSoundTimer(x),
-            (0xF, x, 1, 0xE) => AddI(x),
-            (0xF, x, 2, 9) => SetSpriteAddr(x),
-            (0xF, x, 3, 3) => StoreBcd(x),
-            (0xF, x, 5, 5) => StoreRegs(x),
-            (0xF, x, 6, 5) => LoadRegs(x),
-            (_, _, _, _) => Nop,
+    // Return the instruction at pc from the decode cache, fetching and decoding it on a
+    // miss, and advance pc.
+    fn fetch_decoded(&mut self) -> Instruction {
+        let pc = self.pc as usize;
+        let insn = match self.decode_cache[pc] {
+            Some(insn) => insn,
+            None => {
+                let insn = decode(self.fetch());
+                self.decode_cache[pc] = Some(insn);
+                insn
+            }
         };
-        trace!("Decoded instruction {:?}", decoded_insn);
-        decoded_insn
+        self.pc += 2;
+        insn
+    }
+
+    fn write_memory(&mut self, addr: usize, value: u8) {
+        self.memory[addr] = value;
+        // Both the instruction starting at addr and the one starting a byte earlier
+        // contain the modified byte.
+        self.decode_cache[addr] = None;
+        if addr > 0 {
+            self.decode_cache[addr - 1] = None;
+        }
     }
 
     fn execute(&mut self, insn: Instruction) {
+        self.apply_key_events();
         match insn {
             Nop => (),
 
             Clear => {
-                let frame = self.pixels.frame_mut();
-                for pixel in frame.chunks_exact_mut(4) {
-                    pixel[0] = 0x00; // R
-                    pixel[1] = 0x00; // G
-                    pixel[2] = 0x00; // B
-                    pixel[3] = 0xff; // A
-                }
+                self.display.clear(self.planes);
             }
 
             Return => {
@@ -243,6 +567,18 @@ impl Interpreter {
                 self.pc = address;
             }
 
+            // SCHIP display modes. Switching clears the screen, and with it the last
+            // sprite drawn.
+            LoRes => {
+                self.display = Display::new(CHIP8_WIDTH, CHIP8_HEIGHT);
+                self.last_draw = None;
+            }
+
+            HiRes => {
+                self.display = Display::new(SCHIP_HIRES_WIDTH, SCHIP_HIRES_HEIGHT);
+                self.last_draw = None;
+            }
+
             Jump(addr) => {
                 self.pc = addr;
             }
@@ -258,7 +594,8 @@ impl Interpreter {
             }
 
             JumpOff(addr) => {
-                self.pc = self.v[0] as u16 + addr;
+                let reg = if self.quirks.jump { addr >> 8 } else { 0 };
+                self.pc = self.v[reg as usize] as u16 + addr;
             }
 
             AddI(reg) => {
@@ -270,19 +607,21 @@ impl Interpreter {
                 for i in 0..=last_index {
                     self.v[i] = self.memory[self.i as usize + i]
                 }
+                self.increment_i(reg);
             }
 
             StoreRegs(reg) => {
                 let last_index = reg as usize;
                 for i in 0..=last_index {
-                    self.memory[self.i as usize + i] = self.v[i];
+                    self.write_memory(self.i as usize + i, self.v[i]);
                 }
+                self.increment_i(reg);
             }
 
             StoreBcd(reg) => {
                 let mut value = self.v[reg as usize];
                 for i in (0..=2).rev() {
-                    self.memory[(self.i + i) as usize] = value % 10;
+                    self.write_memory((self.i + i) as usize, value % 10);
                     value /= 10;
                 }
             }
@@ -307,10 +646,13 @@ impl Interpreter {
             }
 
             WaitKeypress(reg) => {
-                if let Some(key) = self.key_pressed {
-                    self.keypad[key as usize] = false;
-                    self.v[reg as usize] = key as u8;
+                if let KeyWait::Done(key) = self.key_wait {
+                    self.v[reg as usize] = key;
+                    self.key_wait = KeyWait::Idle;
                 } else {
+                    if self.key_wait == KeyWait::Idle {
+                        self.key_wait = KeyWait::Press;
+                    }
                     self.pc -= 2;
                 }
             }
@@ -327,20 +669,41 @@ impl Interpreter {
             LoadSoundTimer(reg) => {
                 let value = self.v[reg as usize];
                 self.sound_timer = value;
+                if let Some(beeper) = &self.beeper {
+                    beeper.beep(value);
+                }
+            }
+
+            // XO-CHIP audio.
+            LoadPattern => {
+                let start = self.i as usize;
+                let mut buffer = [0; 16];
+                buffer.copy_from_slice(&self.memory[start..start + 16]);
+                self.set_pattern(buffer);
             }
 
-            Shl(src_dst) => {
-                let src_dst = src_dst as usize;
-                let vf = (self.v[src_dst] >> 7) & 0x1;
-                self.v[src_dst] <<= 1;
-                self.v[0xf] = vf;
+            SetPitch(reg) => {
+                self.pitch = self.v[reg as usize];
+                if let Some(pattern) = self.pattern {
+                    self.set_pattern(pattern.buffer);
+                }
+            }
+
+            // XO-CHIP plane selection.
+            Planes(planes) => {
+                self.planes = planes;
             }
 
-            Shr(src_dst) => {
-                let src_dst = src_dst as usize;
-                let vf = self.v[src_dst] & 0x1;
-                self.v[src_dst] >>= 1;
-                self.v[0xf] = vf;
+            Shl(dst, src) => {
+                let value = self.shift_source(dst, src);
+                self.v[dst as usize] = value << 1;
+                self.v[0xf] = (value >> 7) & 0x1;
+            }
+
+            Shr(dst, src) => {
+                let value = self.shift_source(dst, src);
+                self.v[dst as usize] = value >> 1;
+                self.v[0xf] = value & 0x1;
             }
 
             SkipEq(reg0, reg1) => {
@@ -381,14 +744,17 @@ impl Interpreter {
 
             Or(src_dst, src) => {
                 self.v[src_dst as usize] |= self.v[src as usize];
+                self.reset_vf();
             }
 
             And(src_dst, src) => {
                 self.v[src_dst as usize] &= self.v[src as usize];
+                self.reset_vf();
             }
 
             Xor(src_dst, src) => {
                 self.v[src_dst as usize] ^= self.v[src as usize];
+                self.reset_vf();
             }
 
             Add(src_dst, src) => {
@@ -413,67 +779,121 @@ impl Interpreter {
             }
 
             Rnd(reg, value) => {
-                let random_num: u8 = rand::thread_rng().gen_range(0..=255);
+                let random_num: u8 = self.rng.gen_range(0..=255);
                 self.v[reg as usize] = random_num & value;
             }
 
+            Draw(_, _, _) if self.quirks.vblank && !self.vblank => {
+                // Wait for the next frame.
+                self.pc -= 2;
+            }
+
             Draw(x, y, no_lines) => {
+                self.vblank = false;
+                let wrap = self.quirks.wrap;
                 let x: usize = self.v[x as usize] as usize;
                 let y: usize = self.v[y as usize] as usize;
-                let no_lines: usize = no_lines.into();
-
-                let fb = self.pixels.frame_mut();
-                let sprite = &self.memory[self.i as usize..(self.i + no_lines as u16) as usize];
-
-                // sprites are always 8-bit wide
-                let sprite_len = 8;
-                for (j, line) in sprite.iter().enumerate().take(no_lines) {
-                    let yoff = ((y + j) % CHIP8_HEIGHT) * CHIP8_WIDTH;
-                    for i in 0..sprite_len {
-                        let xoff = (x + i) % CHIP8_WIDTH;
-                        let pixel_coord = (xoff + yoff) * 4;
-                        // fb format is RGBA, so convert it to monochrome (0->0, 255->1)
-                        let old_value = if fb[pixel_coord] == 255 { 1 } else { 0 };
-                        let sprite_value = (line >> (sprite_len - 1 - i)) & 0x1;
-                        let mut new_val = sprite_value ^ old_value;
-                        // Convert back to RGBA
-                        if new_val == 1 {
-                            new_val = 255;
-                        }
-                        fb[pixel_coord] = new_val; // R
-                        fb[pixel_coord + 1] = new_val; // G
-                        fb[pixel_coord + 2] = new_val; // B
-                        fb[pixel_coord + 3] = 255; // A
-                                                   // Detect collisions. Happens when both values are set
-                        if old_value == 1 && sprite_value == 1 {
-                            self.v[0xf] = 1;
-                        }
-                    }
+                // SCHIP 16x16 sprites, stored as two bytes per line. On CHIP-8, DXY0
+                // draws an empty sprite.
+                let wide = no_lines == 0 && self.platform.has_wide_sprites();
+                let (width, height, size) = if wide {
+                    (16, 16, 32)
+                } else {
+                    (8, no_lines as usize, no_lines as usize)
+                };
+                // Set VF on collision, i.e. when a set pixel was turned off. With both
+                // XO-CHIP planes selected, the second plane's sprite follows the first's.
+                let mut collision = false;
+                let mut addr = self.i as usize;
+                for plane in (0..PLANES).filter(|plane| self.planes & (1 << plane) != 0) {
+                    let sprite = &self.memory[addr..addr + size];
+                    collision |= if wide {
+                        self.display.draw_wide_sprite(plane, x, y, sprite, wrap)
+                    } else {
+                        self.display.draw_sprite(plane, x, y, sprite, wrap)
+                    };
+                    addr += size;
                 }
-                self.pixels.render().expect("Error while rendering");
+                self.v[0xf] = collision as u8;
+                self.last_draw = Some(DrawInfo {
+                    addr: self.i,
+                    width,
+                    height,
+                    x: x % self.display.width(),
+                    y: y % self.display.height(),
+                    collision,
+                });
             }
         }
-        // Clear any key-presses
-        self.key_pressed = None;
         trace!("Executed instruction {:?}", insn);
+        self.retire_insn();
+    }
+
+    // Operand of a shift: VX with the shift quirk, otherwise VY.
+    fn shift_source(&self, x: Reg, y: Reg) -> u8 {
+        self.v[if self.quirks.shift { x } else { y } as usize]
+    }
+
+    // Advance I past the registers stored or loaded by FX55/FX65, as the quirks say.
+    fn increment_i(&mut self, last: Reg) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        let increment = if self.quirks.memory_increment_by_x {
+            last
+        } else {
+            last + 1
+        };
+        self.i = self.i.wrapping_add(increment as u16);
+    }
+
+    // The logic quirk of the original interpreter, which clobbered VF.
+    fn reset_vf(&mut self) {
+        if self.quirks.logic {
+            self.v[0xf] = 0;
+        }
+    }
+
+    fn retire_insn(&mut self) {
         self.cycle_count = self.cycle_count.wrapping_add(1);
     }
 
     pub fn step(&mut self) {
-        let current_insn = self.fetch();
-        let decoded_insn: Instruction = self.decode(current_insn);
+        self.cycle();
+        self.end_cycle();
+    }
+
+    fn end_cycle(&mut self) {
+        if self.throttle {
+            self.sleep();
+        }
+        self.print_ops();
+    }
+
+    // Run a single fetch/decode/execute cycle and tick the timers, without throttling
+    // or logging. Returns the instruction that was executed.
+    pub fn cycle(&mut self) -> Instruction {
+        let decoded_insn: Instruction = self.fetch_decoded();
         self.execute(decoded_insn);
         self.update_timers();
-        self.beep();
-        self.sleep();
-        self.print_ops();
+        decoded_insn
     }
 
-    fn beep(&self) {
-        if self.sound_timer > 0 {
-            self.sound_sink.play();
-        } else {
-            self.sound_sink.pause();
+    fn set_pattern(&mut self, buffer: [u8; 16]) {
+        let pattern = Pattern {
+            buffer,
+            pitch: self.pitch,
+        };
+        self.pattern = Some(pattern);
+        if let Some(beeper) = &self.beeper {
+            beeper.set_pattern(pattern);
+        }
+    }
+
+    // Cut the current beep short, e.g. when pausing.
+    pub fn mute(&self) {
+        if let Some(beeper) = &self.beeper {
+            beeper.stop();
         }
     }
 
@@ -483,18 +903,261 @@ impl Interpreter {
 
     fn update_timers(&mut self) {
         // Counters are updated at a frequency of 1/60th second.
-        if self.cycle_count % (self.hz / 60) == 0 {
+        if self.cycle_count.is_multiple_of(self.hz / 60) {
             self.delay_timer = self.delay_timer.saturating_sub(1);
             self.sound_timer = self.sound_timer.saturating_sub(1);
+            self.frame_count += 1;
+            self.vblank = true;
+            self.keys_changed = 0;
         }
     }
 
     fn print_ops(&mut self) {
-        if (self.cycle_count % IPS_MEASURE_CYCLE) == 0 {
-            // Divide by ms instead of s to get more accuracy so multiply by 1000.
-            let ips = 1000 * IPS_MEASURE_CYCLE as u128 / self.timer.elapsed().as_millis();
-            debug!("OPS: {}. Cycle count: {}", ips, self.cycle_count);
+        if self.cycle_count.is_multiple_of(IPS_MEASURE_CYCLE) {
+            // At high speeds the cycles may take no measurable time at all.
+            let elapsed = self.timer.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                let ips = IPS_MEASURE_CYCLE as f64 / elapsed;
+                debug!("OPS: {:.0}. Cycle count: {}", ips, self.cycle_count);
+            }
+            if self.throttle {
+                let stats = self.sleeper.stats();
+                debug!(
+                    "Sleeper: {:.1}Hz, lateness avg {:?} max {:?}, dropped frames {}",
+                    stats.effective_hz,
+                    stats.average_lateness,
+                    stats.max_lateness,
+                    stats.dropped_frames
+                );
+                self.sleeper.reset_stats();
+            }
             self.timer = Instant::now();
         }
     }
 }
+
+// Runtime for statically recompiled code (see recompiler.rs). Recompiled blocks
+// perform simple register operations inline and call back into the interpreter for
+// everything touching the display, input, timers or control flow. Which of these
+// methods are used depends on the ROM, hence the dead_code allowance.
+#[cfg(feature = "recompiled")]
+#[allow(dead_code)]
+impl Interpreter {
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn v_mut(&mut self) -> &mut [u8; 16] {
+        &mut self.v
+    }
+
+    pub fn i_mut(&mut self) -> &mut u16 {
+        &mut self.i
+    }
+
+    // Check that the code a block was compiled from has not been overwritten.
+    pub fn code_matches(&self, addr: u16, code: &[u8]) -> bool {
+        let addr = addr as usize;
+        self.memory[addr..addr + code.len()] == *code
+    }
+
+    // Execute insn as if it had been fetched from addr, as a full cycle.
+    pub fn execute_at(&mut self, addr: u16, insn: Instruction) {
+        self.pc = addr + 2;
+        self.execute(insn);
+        self.update_timers();
+        self.end_cycle();
+    }
+
+    // Finish a cycle for an instruction that recompiled code executed inline. Key events
+    // are applied as if the instruction had gone through execute.
+    pub fn retire(&mut self) {
+        self.apply_key_events();
+        self.retire_insn();
+        self.update_timers();
+        self.end_cycle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(rom: &[u8]) -> Interpreter {
+        Interpreter::new().with_seed(0).load_rom(rom).unwrap()
+    }
+
+    fn run_frame(chip: &mut Interpreter) {
+        for _ in 0..chip.hz / 60 {
+            chip.cycle();
+        }
+    }
+
+    // F00A: wait for a key into V0, then loop forever.
+    const WAIT_KEY: [u8; 4] = [0xF0, 0x0A, 0x12, 0x02];
+
+    #[test]
+    fn wait_key_completes_on_release() {
+        let mut chip = run(&WAIT_KEY);
+        run_frame(&mut chip);
+        chip.press_key(7);
+        run_frame(&mut chip);
+        assert_eq!(chip.key_wait, KeyWait::Release(7));
+        assert_eq!(chip.registers().pc, 0x200);
+        chip.release_key(7);
+        run_frame(&mut chip);
+        assert_eq!(chip.key_wait, KeyWait::Idle);
+        assert_eq!(chip.registers().v[0], 7);
+        assert_eq!(chip.registers().pc, 0x202);
+    }
+
+    #[test]
+    fn wait_key_ignores_held_key() {
+        let mut chip = run(&WAIT_KEY);
+        chip.press_key(5);
+        run_frame(&mut chip);
+        assert_eq!(chip.key_wait, KeyWait::Press);
+        chip.release_key(5);
+        run_frame(&mut chip);
+        assert_eq!(chip.key_wait, KeyWait::Press);
+        chip.press_key(3);
+        run_frame(&mut chip);
+        chip.release_key(3);
+        run_frame(&mut chip);
+        assert_eq!(chip.registers().v[0], 3);
+    }
+
+    #[test]
+    fn press_and_release_in_one_frame_are_both_seen() {
+        // Loop until key 5 is down, then set V1 and loop forever.
+        let mut chip = run(&[0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0x61, 0x01, 0x12, 0x08]);
+        chip.press_key(5);
+        chip.release_key(5);
+        run_frame(&mut chip);
+        assert_eq!(chip.registers().v[1], 1);
+        run_frame(&mut chip);
+        assert!(!chip.keypad()[5]);
+    }
+
+    // Calls a subroutine at 0x20C adding 1 to V1, overwrites the byte at addr with
+    // value, and calls it again.
+    fn self_modifying(addr: u16, value: u8) -> Interpreter {
+        let code: [u16; 8] = [
+            0x220C,                // call 0x20C
+            0xA000 | addr,         // I = addr
+            0x6000 | value as u16, // V0 = value
+            0xF055,                // store V0
+            0x220C,                // call 0x20C
+            0x120A,                // loop
+            0x7101,                // 0x20C: V1 += 1
+            0x00EE,                // return
+        ];
+        let rom: Vec<u8> = code.iter().flat_map(|insn| insn.to_be_bytes()).collect();
+        let mut chip = run(&rom);
+        for _ in 0..9 {
+            chip.cycle();
+        }
+        chip
+    }
+
+    #[test]
+    fn writes_invalidate_decoded_instructions() {
+        // V2 += 1
+        let chip = self_modifying(0x20C, 0x72);
+        assert_eq!(chip.registers().v[1], 1);
+        assert_eq!(chip.registers().v[2], 1);
+        // V1 += 2, by changing the second byte of the instruction.
+        let chip = self_modifying(0x20D, 0x02);
+        assert_eq!(chip.registers().v[1], 3);
+    }
+
+    fn run_quirks(rom: &[u8], quirks: Quirks, cycles: usize) -> Interpreter {
+        let mut chip = Interpreter::new()
+            .with_quirks(quirks)
+            .with_seed(0)
+            .load_rom(rom)
+            .unwrap();
+        for _ in 0..cycles {
+            chip.cycle();
+        }
+        chip
+    }
+
+    #[test]
+    fn shift_quirk() {
+        // V0 = 0x01, V1 = 0x06, V0 >>= 1 (or V0 = V1 >> 1).
+        let rom = [0x60, 0x01, 0x61, 0x06, 0x80, 0x16];
+        let chip = run_quirks(&rom, Quirks::default(), 3);
+        assert_eq!(chip.registers().v[0], 0x00);
+        assert_eq!(chip.registers().v[0xF], 1);
+        let quirks = Quirks {
+            shift: false,
+            ..Quirks::default()
+        };
+        let chip = run_quirks(&rom, quirks, 3);
+        assert_eq!(chip.registers().v[0], 0x03);
+        assert_eq!(chip.registers().v[0xF], 0);
+    }
+
+    #[test]
+    fn memory_quirks() {
+        // I = 0x300, store V0-V2.
+        let rom = [0xA3, 0x00, 0xF2, 0x55];
+        let i = |quirks| run_quirks(&rom, quirks, 2).registers().i;
+        let chip8 = Quirks {
+            memory_leave_i_unchanged: false,
+            ..Quirks::default()
+        };
+        assert_eq!(i(Quirks::default()), 0x300);
+        assert_eq!(i(chip8), 0x303);
+        let quirks = Quirks {
+            memory_increment_by_x: true,
+            ..chip8
+        };
+        assert_eq!(i(quirks), 0x302);
+    }
+
+    #[test]
+    fn jump_quirk() {
+        // V0 = 0x10, V3 = 0x20, jump to 0x300 + V0 (or 0x300 + V3).
+        let rom = [0x60, 0x10, 0x63, 0x20, 0xB3, 0x00];
+        let pc = |quirks| run_quirks(&rom, quirks, 3).registers().pc;
+        assert_eq!(pc(Quirks::default()), 0x310);
+        let quirks = Quirks {
+            jump: true,
+            ..Quirks::default()
+        };
+        assert_eq!(pc(quirks), 0x320);
+    }
+
+    #[test]
+    fn logic_quirk() {
+        // VF = 1, V0 |= V1.
+        let rom = [0x6F, 0x01, 0x80, 0x11];
+        let vf = |quirks| run_quirks(&rom, quirks, 2).registers().v[0xF];
+        assert_eq!(vf(Quirks::default()), 1);
+        let quirks = Quirks {
+            logic: true,
+            ..Quirks::default()
+        };
+        assert_eq!(vf(quirks), 0);
+    }
+
+    #[test]
+    fn wrap_quirk() {
+        // Draw the 0 glyph at (62, 0).
+        let rom = [0x60, 0x3E, 0xA0, 0x00, 0xD0, 0x15];
+        let chip = run_quirks(&rom, Quirks::default(), 3);
+        assert!(chip.display().pixel(62, 0) && chip.display().pixel(1, 0));
+        let quirks = Quirks {
+            wrap: false,
+            ..Quirks::default()
+        };
+        let chip = run_quirks(&rom, quirks, 3);
+        assert!(chip.display().pixel(62, 0) && !chip.display().pixel(1, 0));
+    }
+}
@@ -0,0 +1,46 @@
+use super::constants::*;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+// The machine a program was written for. Platforms differ in the amount of memory,
+// and in whether DXY0 draws a 16x16 sprite. The other SUPER-CHIP and XO-CHIP
+// instructions that are emulated (00FE/00FF, F002, FX3A and FN01) work on every
+// platform, and the rest are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Platform {
+    // The original interpreter on the COSMAC VIP.
+    #[default]
+    Chip8,
+    // SUPER-CHIP on the HP 48 calculators.
+    Schip,
+    // Octo's XO-CHIP extension.
+    XoChip,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::Schip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::Schip => CHIP8_MEMORY_SIZE,
+            Platform::XoChip => XOCHIP_MEMORY_SIZE,
+        }
+    }
+
+    // Whether DXY0 draws a SCHIP 16x16 sprite.
+    pub fn has_wide_sprites(self) -> bool {
+        self != Platform::Chip8
+    }
+
+    // Largest ROM that fits in memory, after the interpreter area.
+    pub fn max_rom_size(self) -> usize {
+        self.memory_size() - CHIP8_PROGRAM_START
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+// Behaviours that differ between CHIP-8 interpreters, named as in the CHIP-8 database.
+// Programs written for one interpreter often break on another, so these follow the
+// platform a program was written for. The defaults are what this interpreter has
+// always done, which suits most modern programs:
+//
+//   shift                     8XY6/8XYE shift VX in place, instead of loading VY shifted
+//   memory_increment_by_x     FX55/FX65 increment I by X, instead of X + 1
+//   memory_leave_i_unchanged  FX55/FX65 leave I unchanged (takes precedence)
+//   wrap                      Sprites wrap around the edges, instead of being clipped
+//   jump                      BXNN jumps to XNN + VX, instead of BNNN jumping to NNN + V0
+//   vblank                    Sprites are drawn at most once per 60Hz frame
+//   logic                     8XY1/8XY2/8XY3 reset VF
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quirks {
+    pub shift: bool,
+    pub memory_increment_by_x: bool,
+    pub memory_leave_i_unchanged: bool,
+    pub wrap: bool,
+    pub jump: bool,
+    pub vblank: bool,
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: true,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
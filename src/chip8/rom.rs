@@ -0,0 +1,168 @@
+use super::platform::Platform;
+use std::fmt;
+use std::path::Path;
+
+// Checks that a file is plausibly a CHIP-8 program before loading it: it must fit in
+// the platform's memory, and not be empty or obviously something else, like a source
+// file that was never assembled or an archive that was never unpacked.
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(std::io::Error),
+    Empty,
+    TooLarge { size: usize, platform: Platform },
+    NotChip8(&'static str),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::Empty => write!(f, "the file is empty"),
+            RomError::TooLarge { size, platform } => write!(
+                f,
+                "the program is {} bytes, but at most {} bytes fit in {} memory",
+                size,
+                platform.max_rom_size(),
+                platform.name()
+            ),
+            RomError::NotChip8(kind) => write!(f, "this looks like {}, not a CHIP-8 program", kind),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<std::io::Error> for RomError {
+    fn from(e: std::io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+impl From<RomError> for std::io::Error {
+    fn from(e: RomError) -> Self {
+        match e {
+            RomError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+// Magic numbers of file formats that turn up where ROMs are expected. Two byte magic
+// numbers are also valid instructions, so they are only matched along with more of the
+// header: the compression method for gzip, and the PE header for Windows executables.
+const SIGNATURES: [(&[u8], &str); 6] = [
+    (b"PK\x03\x04", "a ZIP archive"),
+    (b"\x1f\x8b\x08", "a gzip archive"),
+    (b"\x89PNG", "a PNG image"),
+    (b"%PDF", "a PDF document"),
+    (b"\x7fELF", "an ELF executable"),
+    (b"\xef\xbb\xbf", "a text file"),
+];
+
+// Whether this is a Windows executable: a DOS header, whose e_lfanew field at 0x3C
+// points to a PE header.
+fn is_windows_executable(rom: &[u8]) -> bool {
+    let Some(&[a, b, c, d]) = rom.get(0x3C..0x40) else {
+        return false;
+    };
+    let offset = u32::from_le_bytes([a, b, c, d]) as usize;
+    rom.starts_with(b"MZ")
+        && rom
+            .get(offset..)
+            .is_some_and(|header| header.starts_with(b"PE\0\0"))
+}
+
+// Programs this small could be made of printable characters by chance.
+const MIN_TEXT_SIZE: usize = 16;
+
+pub fn validate(rom: &[u8], platform: Platform) -> Result<(), RomError> {
+    if rom.is_empty() {
+        return Err(RomError::Empty);
+    }
+    if rom.len() > platform.max_rom_size() {
+        return Err(RomError::TooLarge {
+            size: rom.len(),
+            platform,
+        });
+    }
+    if let Some((_, kind)) = SIGNATURES.iter().find(|(magic, _)| rom.starts_with(magic)) {
+        return Err(RomError::NotChip8(kind));
+    }
+    if is_windows_executable(rom) {
+        return Err(RomError::NotChip8("a Windows executable"));
+    }
+    // Octo and other assemblers take text, but programs always contain some bytes
+    // outside the printable range, if only in their first instruction.
+    let text = |&byte: &u8| matches!(byte, b'\t' | b'\n' | b'\r' | b' '..=b'~');
+    if rom.len() >= MIN_TEXT_SIZE && rom.iter().all(text) {
+        return Err(RomError::NotChip8(
+            "a text file, such as unassembled source",
+        ));
+    }
+    Ok(())
+}
+
+// Read and validate a ROM.
+pub fn read(path: impl AsRef<Path>, platform: Platform) -> Result<Vec<u8>, RomError> {
+    let rom = std::fs::read(path)?;
+    validate(&rom, platform)?;
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_programs() {
+        assert!(validate(&[0x00, 0xE0, 0x12, 0x00], Platform::Chip8).is_ok());
+        assert!(validate(&[0x12; 3584], Platform::Chip8).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized() {
+        assert!(matches!(
+            validate(&[], Platform::Chip8),
+            Err(RomError::Empty)
+        ));
+        assert!(matches!(
+            validate(&[0x12; 3585], Platform::Chip8),
+            Err(RomError::TooLarge { size: 3585, .. })
+        ));
+        assert!(validate(&[0x12; 3585], Platform::XoChip).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert!(matches!(
+            validate(b"\x89PNG\r\n\x1a\n", Platform::Chip8),
+            Err(RomError::NotChip8("a PNG image"))
+        ));
+        assert!(matches!(
+            validate(b"; Octo source\n: main\n  loop again\n", Platform::Chip8),
+            Err(RomError::NotChip8(_))
+        ));
+        // Short programs may consist of printable bytes.
+        assert!(validate(b"a`", Platform::Chip8).is_ok());
+    }
+
+    #[test]
+    fn two_byte_magic_numbers_need_headers() {
+        // JP 0xF8B, and a 4XNN skip on V0xD.
+        assert!(validate(&[0x1F, 0x8B, 0x60, 0x01], Platform::Chip8).is_ok());
+        assert!(validate(&[0x4D, 0x5A, 0x60, 0x01], Platform::Chip8).is_ok());
+        assert!(matches!(
+            validate(&[0x1F, 0x8B, 0x08, 0x00], Platform::Chip8),
+            Err(RomError::NotChip8("a gzip archive"))
+        ));
+        let mut exe = vec![0; 0x84];
+        exe[..2].copy_from_slice(b"MZ");
+        exe[0x3C] = 0x80;
+        exe[0x80..].copy_from_slice(b"PE\0\0");
+        assert!(matches!(
+            validate(&exe, Platform::Chip8),
+            Err(RomError::NotChip8("a Windows executable"))
+        ));
+    }
+}
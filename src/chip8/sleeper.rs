@@ -2,24 +2,70 @@ use log::trace;
 use std::thread;
 use std::time::{Duration, Instant};
 
-// Use a helper class for accumulating delay values until they reach a certain threshold.
-// This way we can compensate for the inaccuracies of sleep() for low values (< 1ms).
-// We can also compensate for when the VM runs too slow, by reducing throttling.
+// Helper class that paces the VM against an absolute deadline. It is called once per
+// duty cycle, but only waits once per batch of calls, e.g. a frame's worth of cycles,
+// so that waits are long enough to sleep through. Waiting is a hybrid of
+// thread::sleep() for the bulk of the time, and spin-waiting for the last stretch,
+// since sleep() is too coarse for sub-ms values. Because the deadline is absolute,
+// small errors do not accumulate.
 #[derive(Debug)]
 pub struct Sleeper {
     duty_cycle: Duration,
-    threshold: Duration,
-    debt: Duration,
-    timer: Instant,
+    // Calls per wait, and calls since the last wait.
+    batch: u32,
+    pending: u32,
+    // Below this much remaining time we spin instead of sleeping.
+    spin_threshold: Duration,
+    // If we fall further behind than this, give up catching up and drop the backlog.
+    max_lag: Duration,
+    // Duration of a display frame, used to express dropped time in frames.
+    frame: Duration,
+    // Set on the first call, so that start-up time is not counted as lateness.
+    deadline: Option<Instant>,
+    stats: Stats,
+}
+
+#[derive(Debug)]
+struct Stats {
+    since: Instant,
+    ticks: u64,
+    total_lateness: Duration,
+    max_lateness: Duration,
+    dropped_frames: u64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            since: Instant::now(),
+            ticks: 0,
+            total_lateness: Duration::ZERO,
+            max_lateness: Duration::ZERO,
+            dropped_frames: 0,
+        }
+    }
+}
+
+// Snapshot of the pacing statistics since they were last reset.
+#[derive(Debug, Clone, Copy)]
+pub struct SleeperStats {
+    pub average_lateness: Duration,
+    pub max_lateness: Duration,
+    pub dropped_frames: u64,
+    pub effective_hz: f64,
 }
 
 impl Sleeper {
     pub fn new() -> Self {
         Sleeper {
-            threshold: Duration::from_millis(25),
-            timer: Instant::now(),
-            debt: Duration::ZERO,
             duty_cycle: Duration::ZERO,
+            batch: 1,
+            pending: 0,
+            spin_threshold: Duration::from_micros(500),
+            max_lag: Duration::from_millis(100),
+            frame: Duration::from_secs_f64(1.0 / 60.0),
+            deadline: None,
+            stats: Stats::new(),
         }
     }
 
@@ -29,50 +75,115 @@ impl Sleeper {
         self
     }
 
+    pub fn with_batch(mut self, calls: u32) -> Self {
+        self.batch = calls.max(1);
+        self
+    }
+
     pub fn sleep(&mut self) {
-        let elapsed = self.timer.elapsed();
-        trace!("Current sleep debt {:?}", self.debt);
-        if let Some(result) =  self.duty_cycle.checked_sub(elapsed) {
+        self.pending += 1;
+        if self.pending < self.batch {
+            return;
+        }
+        self.pending = 0;
+        let step = self.duty_cycle * self.batch;
+        let deadline = *self.deadline.get_or_insert_with(|| Instant::now() + step);
+        if let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
             // We were too fast, so throttle!
-            self.debt += result;
+            if remaining > self.spin_threshold {
+                thread::sleep(remaining - self.spin_threshold);
+            }
+            while Instant::now() < deadline {
+                std::hint::spin_loop();
+            }
+        }
+
+        let now = Instant::now();
+        let lateness = now.saturating_duration_since(deadline);
+        self.record(lateness);
+        if lateness > self.max_lag {
+            // Too slow to catch up by running unthrottled, so drop the backlog.
+            let dropped = (lateness.as_secs_f64() / self.frame.as_secs_f64()) as u64;
             trace!(
-                "Adding {:?} to sleep debt (tgt: {:?}, got: {:?})",
-                result,
-                self.duty_cycle,
-                elapsed
+                "VM is REALLY SLOW! {:?} behind, dropping {} frames",
+                lateness,
+                dropped
             );
+            self.stats.dropped_frames += dropped;
+            self.deadline = Some(now + step);
         } else {
-            // We were too slow, reduce throttling if needed.
-            let slowdown = elapsed - self.duty_cycle;
-            if self.debt >= slowdown {
-                // Re-adjust sleep debt to compensate for slowness
-                trace!(
-                    "VM running slow! Reducing sleep debt by {:?} (tgt: {:?}, got: {:?})",
-                    slowdown,
-                    self.duty_cycle,
-                    elapsed
-                );    
-                self.debt -= slowdown;
-            } else {
-                // Too slow to compensate using sleep debt so reset to zero
-                trace!("VM is REALLY SLOW! Resetting sleep debt to zero...");
-                self.debt = Duration::ZERO;
-            }
-            self.timer = Instant::now();
-            return;
+            self.deadline = Some(deadline + step);
+        }
+    }
+
+    fn record(&mut self, lateness: Duration) {
+        let stats = &mut self.stats;
+        stats.ticks += self.batch as u64;
+        if !lateness.is_zero() {
+            stats.total_lateness += lateness;
+            stats.max_lateness = stats.max_lateness.max(lateness);
         }
-        // Time to sleep
-        self.sleep_internal();
-        self.timer = Instant::now();
     }
 
-    fn sleep_internal(&mut self) {
-        if self.debt > self.threshold {
-            trace!("Sleeping for {}ms", (self.debt).as_millis());
-            thread::sleep(self.debt);
-            self.timer = Instant::now();
-            self.debt = Duration::ZERO;
+    pub fn stats(&self) -> SleeperStats {
+        let stats = &self.stats;
+        let waits = (stats.ticks / self.batch as u64).max(1);
+        SleeperStats {
+            average_lateness: Duration::from_secs_f64(
+                stats.total_lateness.as_secs_f64() / waits as f64,
+            ),
+            max_lateness: stats.max_lateness,
+            dropped_frames: stats.dropped_frames,
+            effective_hz: stats.ticks as f64 / stats.since.elapsed().as_secs_f64(),
         }
     }
 
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_advances_by_duty_cycle() {
+        let mut sleeper = Sleeper::new().with_frequency(1000);
+        sleeper.sleep();
+        let first = sleeper.deadline.unwrap();
+        for _ in 0..4 {
+            sleeper.sleep();
+        }
+        // Deadlines are absolute, so time spent between calls does not add up.
+        assert_eq!(sleeper.deadline.unwrap() - first, Duration::from_millis(4));
+        assert_eq!(sleeper.stats.dropped_frames, 0);
+    }
+
+    #[test]
+    fn waits_once_per_batch() {
+        let mut sleeper = Sleeper::new().with_frequency(1000).with_batch(4);
+        for _ in 0..3 {
+            sleeper.sleep();
+        }
+        assert!(sleeper.deadline.is_none());
+        sleeper.sleep();
+        let first = sleeper.deadline.unwrap();
+        for _ in 0..8 {
+            sleeper.sleep();
+        }
+        assert_eq!(sleeper.deadline.unwrap() - first, Duration::from_millis(8));
+        assert_eq!(sleeper.stats.ticks, 12);
+    }
+
+    #[test]
+    fn drops_backlog_when_far_behind() {
+        let mut sleeper = Sleeper::new().with_frequency(1000);
+        let behind = Instant::now() - Duration::from_millis(200);
+        sleeper.deadline = Some(behind);
+        sleeper.sleep();
+        // 200ms is 12 frames, plus however long the call took.
+        assert!(sleeper.stats.dropped_frames >= 12);
+        assert!(sleeper.deadline.unwrap() > Instant::now() - Duration::from_millis(100));
+    }
 }
@@ -0,0 +1,232 @@
+use super::constants::*;
+use clap::ValueEnum;
+use rodio::{OutputStream, Source};
+use serde::Deserialize;
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The beeper. Rather than switching an audio sink on and off from the interpreter, the
+// interpreter tells the audio thread how long to beep for whenever the sound timer is
+// set, and the audio thread counts that down in samples. Beeps therefore last exactly
+// as long as the sound timer says, and the volume is ramped up and down at the start
+// and end of each beep to avoid clicks.
+//
+// Programs play the configured tone, until they load an XO-CHIP audio pattern. From
+// then on, the 128 bit pattern is played in a loop instead, at a rate set by the pitch
+// register.
+
+pub const SAMPLE_RATE: u32 = 44100;
+// Duration of the attack and release ramps.
+const ENVELOPE_SECONDS: f32 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Waveform {
+    Square,
+    #[default]
+    Sine,
+    Triangle,
+    Noise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    // Between 0 and 1.
+    pub volume: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Tone {
+            waveform: Waveform::default(),
+            frequency: CHIP8_BEEP_FREQUENCY,
+            volume: 0.5,
+        }
+    }
+}
+
+// XO-CHIP audio pattern buffer (loaded with F002) and pitch register (set with FX3A).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern {
+    pub buffer: [u8; 16],
+    pub pitch: u8,
+}
+
+impl Pattern {
+    pub const DEFAULT_PITCH: u8 = 64;
+    const BITS: f32 = 128.0;
+
+    // Playback rate in bits per second, 4000 at the default pitch.
+    fn rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    fn bit(&self, n: usize) -> bool {
+        self.buffer[n / 8] & (0x80 >> (n % 8)) != 0
+    }
+}
+
+// Generates the beeper's samples at SAMPLE_RATE, one at a time.
+#[derive(Debug, Clone)]
+pub struct Synth {
+    tone: Tone,
+    pattern: Option<Pattern>,
+    // Position within the current period (or pattern), between 0 and 1.
+    phase: f32,
+    // Envelope level, between 0 and 1.
+    level: f32,
+    noise: u32,
+    noise_sample: f32,
+}
+
+impl Synth {
+    pub fn new(tone: Tone) -> Self {
+        Synth {
+            tone,
+            pattern: None,
+            phase: 0.0,
+            level: 0.0,
+            noise: 0x1234_5678,
+            noise_sample: 0.0,
+        }
+    }
+
+    // Play an XO-CHIP pattern instead of the tone.
+    pub fn set_pattern(&mut self, pattern: Option<Pattern>) {
+        self.pattern = pattern;
+    }
+
+    // The next sample, with the beeper switched on or off.
+    pub fn next_sample(&mut self, on: bool) -> f32 {
+        let step = 1.0 / (ENVELOPE_SECONDS * SAMPLE_RATE as f32);
+        self.level = if on {
+            (self.level + step).min(1.0)
+        } else {
+            (self.level - step).max(0.0)
+        };
+        if self.level == 0.0 {
+            // Start every beep at the same point of the waveform.
+            self.phase = 0.0;
+            return 0.0;
+        }
+        if let Some(pattern) = self.pattern {
+            let bit = pattern.bit((self.phase * Pattern::BITS) as usize);
+            self.phase = (self.phase + pattern.rate() / Pattern::BITS / SAMPLE_RATE as f32).fract();
+            let value = if bit { 1.0 } else { -1.0 };
+            return value * self.level * self.tone.volume.clamp(0.0, 1.0);
+        }
+        let value = match self.tone.waveform {
+            Waveform::Square if self.phase < 0.5 => 1.0,
+            Waveform::Square => -1.0,
+            Waveform::Sine => (self.phase * TAU).sin(),
+            Waveform::Triangle => 4.0 * (self.phase - 0.5).abs() - 1.0,
+            Waveform::Noise => self.noise_sample,
+        };
+        self.phase += self.tone.frequency / SAMPLE_RATE as f32;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            // Noise is a new random level every period, so that its pitch follows the
+            // frequency too.
+            self.noise ^= self.noise << 13;
+            self.noise ^= self.noise >> 17;
+            self.noise ^= self.noise << 5;
+            self.noise_sample = self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0;
+        }
+        value * self.level * self.tone.volume.clamp(0.0, 1.0)
+    }
+}
+
+// Audio output for the interpreter.
+pub struct Beeper {
+    // Playback stops when the stream is dropped.
+    _stream: OutputStream,
+    // Samples left to play at full volume.
+    remaining: Arc<AtomicU32>,
+    pattern: Arc<PatternSlot>,
+}
+
+// A pattern handed over to the audio thread, which only takes the lock when it changed.
+#[derive(Default)]
+struct PatternSlot {
+    pattern: Mutex<Option<Pattern>>,
+    changed: AtomicBool,
+}
+
+impl Beeper {
+    // Open the default audio output device.
+    pub fn new(tone: Tone) -> Result<Beeper, String> {
+        let (stream, handle) = OutputStream::try_default().map_err(|e| e.to_string())?;
+        let remaining = Arc::new(AtomicU32::new(0));
+        let pattern = Arc::new(PatternSlot::default());
+        let source = BeeperSource {
+            synth: Synth::new(tone),
+            remaining: remaining.clone(),
+            pattern: pattern.clone(),
+        };
+        handle.play_raw(source).map_err(|e| e.to_string())?;
+        Ok(Beeper {
+            _stream: stream,
+            remaining,
+            pattern,
+        })
+    }
+
+    // Beep for the given number of 60Hz timer ticks, replacing any current beep.
+    pub fn beep(&self, ticks: u8) {
+        let samples = ticks as u32 * SAMPLE_RATE / 60;
+        self.remaining.store(samples, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.remaining.store(0, Ordering::Relaxed);
+    }
+
+    pub fn set_pattern(&self, pattern: Pattern) {
+        *self.pattern.pattern.lock().unwrap() = Some(pattern);
+        self.pattern.changed.store(true, Ordering::Release);
+    }
+}
+
+struct BeeperSource {
+    synth: Synth,
+    remaining: Arc<AtomicU32>,
+    pattern: Arc<PatternSlot>,
+}
+
+impl Iterator for BeeperSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.pattern.changed.swap(false, Ordering::Acquire) {
+            self.synth
+                .set_pattern(*self.pattern.pattern.lock().unwrap());
+        }
+        let on = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        Some(self.synth.next_sample(on))
+    }
+}
+
+impl Source for BeeperSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
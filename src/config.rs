@@ -0,0 +1,292 @@
+use crate::chip8::constants::*;
+use crate::chip8::sound::{Tone, Waveform};
+use crate::chip8::{Platform, Quirks};
+use crate::database::Database;
+use crate::filter::Filter;
+use crate::keymap::{KeyboardMap, Layout};
+use crate::render::{Color, Theme};
+use clap::Args;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use winit::event::VirtualKeyCode;
+
+// Settings are detected from the ROM (see database.rs), then read from a TOML config
+// file, globally and per ROM, and then from the command line, with later sources
+// overriding earlier ones. For example:
+//
+//   theme = "amber"
+//
+//   [roms."pong.ch8"]
+//   theme = "lcd"
+//
+//   [roms.<sha1 of the ROM>]
+//   fg = "#33ff66"
+//
+//   [roms."brix.ch8".keys]
+//   Left = 0x4
+//   Right = 0x6
+//
+//   [roms."blinky.ch8".quirks]
+//   shift = false
+//
+// ROM sections are matched by file name or by the SHA-1 hash of the ROM contents. Key
+// mappings go from host keys (winit key names) to CHIP-8 keys, and are added on top of
+// the layout preset, so several host keys can map to the same CHIP-8 key. Unlike the
+// preset, they follow the keyboard layout: "A" is whichever key types an A. Quirks are
+// described in chip8/quirks.rs.
+pub const DEFAULT_CONFIG_PATH: &str = "chip8.toml";
+
+// Far beyond what any program needs, while keeping cycles per second in range.
+pub const MAX_CYCLES_PER_FRAME: u32 = 100_000;
+
+#[derive(Args, Debug, Default, Clone, Deserialize)]
+pub struct Settings {
+    /// Platform the program was written for.
+    #[arg(long, value_enum)]
+    pub platform: Option<Platform>,
+    /// Instructions executed per 60Hz frame, up to 100000. Defaults to about 1000 per
+    /// second.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=MAX_CYCLES_PER_FRAME as i64))]
+    pub cycles_per_frame: Option<u32>,
+    /// Color theme.
+    #[arg(long)]
+    pub theme: Option<Theme>,
+    /// Background color (#rrggbb).
+    #[arg(long)]
+    pub bg: Option<Color>,
+    /// Foreground color (#rrggbb).
+    #[arg(long)]
+    pub fg: Option<Color>,
+    /// Color of the second XO-CHIP bitplane (#rrggbb).
+    #[arg(long)]
+    pub fg2: Option<Color>,
+    /// Color where both XO-CHIP bitplanes are set (#rrggbb).
+    #[arg(long)]
+    pub blend: Option<Color>,
+    /// Fade pixels out like CRT phosphor, keeping this fraction (0-1) of their
+    /// brightness on each frame.
+    #[arg(long)]
+    pub phosphor: Option<f32>,
+    /// Pixel art upscaling filter.
+    #[arg(long, value_enum)]
+    pub filter: Option<Filter>,
+    /// Double the lines and darken every other one to this fraction (0-1) of its
+    /// brightness, like CRT scanlines.
+    #[arg(long)]
+    pub scanlines: Option<f32>,
+    /// Waveform of the beeper.
+    #[arg(long, value_enum)]
+    pub waveform: Option<Waveform>,
+    /// Pitch of the beeper, in Hz.
+    #[arg(long)]
+    pub beep_frequency: Option<f32>,
+    /// Volume of the beeper (0-1).
+    #[arg(long)]
+    pub volume: Option<f32>,
+    /// Keyboard layout preset, by physical key position.
+    #[arg(long, value_enum)]
+    pub layout: Option<Layout>,
+    /// Keep keys held after they are released, until another key is pressed.
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub sticky_keys: Option<bool>,
+    /// CHIP-8 keys (0-F) that are held by one press and released by the next.
+    #[arg(long, value_delimiter = ',', value_parser = parse_key)]
+    pub toggle_keys: Option<Vec<u8>>,
+    /// Repeat held keys at this interval, in milliseconds.
+    #[arg(long)]
+    pub repeat_interval: Option<u32>,
+    /// Delay before held keys start repeating, in milliseconds. Defaults to the
+    /// repeat interval.
+    #[arg(long)]
+    pub repeat_delay: Option<u32>,
+    /// Flash the border while the sound timer is active.
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub visual_beep: Option<bool>,
+    /// High contrast colors and a large border, without phosphor and scanline
+    /// effects.
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub high_contrast: Option<bool>,
+    /// Width of the border around the display, in CHIP-8 pixels.
+    #[arg(long)]
+    pub border: Option<u32>,
+    // Only available in the config file. A quirks table replaces the detected quirks,
+    // with any quirks left out at their defaults.
+    #[arg(skip)]
+    pub quirks: Option<Quirks>,
+    #[arg(skip)]
+    #[serde(default)]
+    pub keys: HashMap<VirtualKeyCode, u8>,
+}
+
+impl Settings {
+    // Override these settings with the ones given in other.
+    pub fn merge(&mut self, other: &Settings) {
+        self.platform = other.platform.or(self.platform);
+        self.cycles_per_frame = other.cycles_per_frame.or(self.cycles_per_frame);
+        self.quirks = other.quirks.or(self.quirks);
+        self.theme = other.theme.or(self.theme);
+        self.bg = other.bg.or(self.bg);
+        self.fg = other.fg.or(self.fg);
+        self.fg2 = other.fg2.or(self.fg2);
+        self.blend = other.blend.or(self.blend);
+        self.phosphor = other.phosphor.or(self.phosphor);
+        self.filter = other.filter.or(self.filter);
+        self.scanlines = other.scanlines.or(self.scanlines);
+        self.waveform = other.waveform.or(self.waveform);
+        self.beep_frequency = other.beep_frequency.or(self.beep_frequency);
+        self.volume = other.volume.or(self.volume);
+        self.layout = other.layout.or(self.layout);
+        self.sticky_keys = other.sticky_keys.or(self.sticky_keys);
+        self.toggle_keys = other.toggle_keys.clone().or(self.toggle_keys.take());
+        self.repeat_interval = other.repeat_interval.or(self.repeat_interval);
+        self.repeat_delay = other.repeat_delay.or(self.repeat_delay);
+        self.visual_beep = other.visual_beep.or(self.visual_beep);
+        self.high_contrast = other.high_contrast.or(self.high_contrast);
+        self.border = other.border.or(self.border);
+        self.keys.extend(&other.keys);
+    }
+
+    // The layout preset with the configured mappings applied.
+    pub fn keyboard_map(&self) -> KeyboardMap {
+        let keys = self.keys.iter().map(|(&host, &key)| (host, key as u32));
+        KeyboardMap::new(self.layout.unwrap_or_default(), keys.collect())
+    }
+
+    // Interpreter speed, in cycles per second.
+    pub fn hz(&self) -> u32 {
+        self.cycles_per_frame.map_or(CHIP8_SPEED_HZ, |cycles| {
+            cycles.clamp(1, MAX_CYCLES_PER_FRAME) * 60
+        })
+    }
+
+    pub fn tone(&self) -> Tone {
+        let default = Tone::default();
+        Tone {
+            waveform: self.waveform.unwrap_or(default.waveform),
+            frequency: self.beep_frequency.unwrap_or(default.frequency),
+            volume: self.volume.unwrap_or(default.volume),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    global: Settings,
+    #[serde(default)]
+    roms: HashMap<String, Settings>,
+}
+
+impl Config {
+    // Load the config file at path. A missing file results in an empty config.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let config: Config = match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| e.to_string())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e.to_string()),
+        };
+        let sections = std::iter::once(&config.global).chain(config.roms.values());
+        for settings in sections {
+            for (host, key) in &settings.keys {
+                if *key > 0xF {
+                    return Err(format!("Invalid CHIP-8 key {:#x} for {:?}", key, host));
+                }
+            }
+            if let Some(key) = settings
+                .toggle_keys
+                .iter()
+                .flatten()
+                .find(|&&key| key > 0xF)
+            {
+                return Err(format!("Invalid CHIP-8 key {:#x} in toggle_keys", key));
+            }
+            if let Some(cycles) = settings
+                .cycles_per_frame
+                .filter(|cycles| !(1..=MAX_CYCLES_PER_FRAME).contains(cycles))
+            {
+                return Err(format!(
+                    "Invalid cycles_per_frame {}, must be 1 to {}",
+                    cycles, MAX_CYCLES_PER_FRAME
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    // Settings for a ROM, given its file name and contents.
+    pub fn settings_for(&self, name: &str, rom: &[u8]) -> Settings {
+        let mut settings = self.global.clone();
+        let file_name = Path::new(name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let keys = [file_name, Some(rom_hash(rom))];
+        for key in keys.iter().flatten() {
+            if let Some(rom_settings) = self.roms.get(key) {
+                settings.merge(rom_settings);
+            }
+        }
+        settings
+    }
+}
+
+// Settings for running a ROM headless: detected from the ROM with the given database,
+// or the bundled one, then read from the config file and the command line.
+pub fn headless_settings(
+    binary: &str,
+    rom: &[u8],
+    config: &str,
+    database: Option<&str>,
+    args: &Settings,
+) -> std::io::Result<Settings> {
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let config = Config::load(Path::new(config)).map_err(invalid)?;
+    let database = match database {
+        Some(path) => Database::load(Path::new(path)).map_err(invalid)?,
+        None => Database::bundled(),
+    };
+    let mut settings = database.detect(rom).settings;
+    settings.merge(&config.settings_for(binary, rom));
+    settings.merge(args);
+    Ok(settings)
+}
+
+// Parse a CHIP-8 key given as a hex digit.
+fn parse_key(s: &str) -> Result<u8, String> {
+    match u8::from_str_radix(s, 16) {
+        Ok(key) if key <= 0xF => Ok(key),
+        _ => Err(format!("Invalid CHIP-8 key {s}, expected 0-F")),
+    }
+}
+
+pub fn rom_hash(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_stays_in_range() {
+        let hz = |cycles_per_frame| {
+            Settings {
+                cycles_per_frame,
+                ..Settings::default()
+            }
+            .hz()
+        };
+        assert_eq!(hz(None), CHIP8_SPEED_HZ);
+        assert_eq!(hz(Some(0)), 60);
+        assert_eq!(hz(Some(u32::MAX)), MAX_CYCLES_PER_FRAME * 60);
+    }
+
+    #[test]
+    fn rejects_out_of_range_speed() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "[roms.\"game.ch8\"]\ncycles_per_frame = 0\n").unwrap();
+        let result = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().contains("cycles_per_frame"));
+    }
+}
@@ -0,0 +1,355 @@
+use crate::chip8::{Platform, Quirks};
+use crate::config::{rom_hash, Settings, MAX_CYCLES_PER_FRAME};
+use crate::recompiler::reachable_code;
+use crate::render::Color;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use winit::event::VirtualKeyCode;
+
+// Detects the settings a ROM needs, so that games run without knowing which platform
+// they were written for. Known ROMs are looked up by SHA-1 hash in a program database,
+// in the format of the community CHIP-8 database's programs.json:
+//
+//   [{
+//     "title": "Some Game",
+//     "authors": ["Someone"],
+//     "roms": {
+//       "<sha1 of the ROM>": {
+//         "platforms": ["superchip"],
+//         "quirkyPlatforms": {"superchip": {"shift": false}},
+//         "tickrate": 30,
+//         "colors": {"pixels": ["#000000", "#ffffff"]},
+//         "keys": {"left": 4, "right": 6, "a": 5}
+//       }
+//     }
+//   }]
+//
+// Entries give the platforms a ROM runs on, in order of preference, from which the
+// platform, quirks and speed follow, plus the display colors and the CHIP-8 keys for
+// game controls, which are mapped to the arrow keys, space (a) and shift (b). Any other
+// fields are ignored. The bundled database (database.json) is meant to hold a copy of
+// programs.json, or a subset of it, and can be replaced with another with --database.
+//
+// Unknown ROMs are checked for the SUPER-CHIP and XO-CHIP instructions that are
+// emulated instead. ROMs that use neither run with the default settings.
+
+const BUNDLED: &str = include_str!("database.json");
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Database {
+    programs: Vec<Program>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate: Option<u32>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Colors {
+    // Background, foreground, second XO-CHIP bitplane and both bitplanes.
+    #[serde(default)]
+    pixels: Vec<Color>,
+}
+
+// What was found out about a ROM.
+#[derive(Debug, Default)]
+pub struct Detected {
+    // Title and authors, for known ROMs.
+    pub title: Option<String>,
+    pub settings: Settings,
+}
+
+impl Database {
+    pub fn bundled() -> Database {
+        serde_json::from_str(BUNDLED).expect("Invalid bundled ROM database")
+    }
+
+    pub fn load(path: &Path) -> Result<Database, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    pub fn detect(&self, rom: &[u8]) -> Detected {
+        let hash = rom_hash(rom);
+        let known = self
+            .programs
+            .iter()
+            .find_map(|program| Some((program, program.roms.get(&hash)?)));
+        match known {
+            Some((program, entry)) => Detected {
+                title: Some(program.title()),
+                settings: entry.settings(),
+            },
+            None => Detected {
+                title: None,
+                settings: guess(rom),
+            },
+        }
+    }
+}
+
+impl Program {
+    fn title(&self) -> String {
+        match self.authors.as_slice() {
+            [] => self.title.clone(),
+            [authors @ .., last] if !authors.is_empty() => {
+                format!("{} by {} and {}", self.title, authors.join(", "), last)
+            }
+            authors => format!("{} by {}", self.title, authors.join(", ")),
+        }
+    }
+}
+
+impl RomEntry {
+    fn settings(&self) -> Settings {
+        let mut settings = Settings::default();
+        let preset = self.platforms.iter().find_map(|id| Some((id, preset(id)?)));
+        if let Some((id, (platform, quirks, tickrate))) = preset {
+            let overrides = self.quirky_platforms.get(id).copied().unwrap_or_default();
+            settings.platform = Some(platform);
+            settings.quirks = Some(overrides.apply(quirks));
+            settings.cycles_per_frame = Some(tickrate);
+        }
+        settings.cycles_per_frame = self
+            .tickrate
+            .map(|tickrate| tickrate.clamp(1, MAX_CYCLES_PER_FRAME))
+            .or(settings.cycles_per_frame);
+        if let Some(colors) = &self.colors {
+            let mut pixels = colors.pixels.iter().copied();
+            settings.bg = pixels.next();
+            settings.fg = pixels.next();
+            settings.fg2 = pixels.next();
+            settings.blend = pixels.next();
+        }
+        for (control, &key) in &self.keys {
+            if let (Some(host), true) = (control_key(control), key <= 0xF) {
+                settings.keys.insert(host, key);
+            }
+        }
+        settings
+    }
+}
+
+impl QuirkOverrides {
+    fn apply(self, quirks: Quirks) -> Quirks {
+        Quirks {
+            shift: self.shift.unwrap_or(quirks.shift),
+            memory_increment_by_x: self
+                .memory_increment_by_x
+                .unwrap_or(quirks.memory_increment_by_x),
+            memory_leave_i_unchanged: self
+                .memory_leave_i_unchanged
+                .unwrap_or(quirks.memory_leave_i_unchanged),
+            wrap: self.wrap.unwrap_or(quirks.wrap),
+            jump: self.jump.unwrap_or(quirks.jump),
+            vblank: self.vblank.unwrap_or(quirks.vblank),
+            logic: self.logic.unwrap_or(quirks.logic),
+        }
+    }
+}
+
+// Platform, quirks and cycles per frame for the database's platform ids, as far as
+// they are supported. Hybrid VIP programs need the VIP's machine code routines, and
+// MEGA-CHIP is not emulated.
+fn preset(id: &str) -> Option<(Platform, Quirks, u32)> {
+    let chip8 = Quirks {
+        shift: false,
+        memory_increment_by_x: false,
+        memory_leave_i_unchanged: false,
+        wrap: false,
+        jump: false,
+        vblank: false,
+        logic: false,
+    };
+    let preset = match id {
+        "originalChip8" => (
+            Platform::Chip8,
+            Quirks {
+                vblank: true,
+                logic: true,
+                ..chip8
+            },
+            15,
+        ),
+        "modernChip8" => (Platform::Chip8, chip8, 12),
+        "chip48" | "superchip1" => (
+            Platform::Schip,
+            Quirks {
+                shift: true,
+                memory_increment_by_x: true,
+                jump: true,
+                ..chip8
+            },
+            30,
+        ),
+        "superchip" => (
+            Platform::Schip,
+            Quirks {
+                shift: true,
+                memory_leave_i_unchanged: true,
+                jump: true,
+                ..chip8
+            },
+            30,
+        ),
+        "xochip" => (
+            Platform::XoChip,
+            Quirks {
+                wrap: true,
+                ..chip8
+            },
+            100,
+        ),
+        _ => return None,
+    };
+    Some(preset)
+}
+
+// Host keys for the database's game controls.
+fn control_key(control: &str) -> Option<VirtualKeyCode> {
+    let key = match control {
+        "up" => VirtualKeyCode::Up,
+        "down" => VirtualKeyCode::Down,
+        "left" => VirtualKeyCode::Left,
+        "right" => VirtualKeyCode::Right,
+        "a" => VirtualKeyCode::Space,
+        "b" => VirtualKeyCode::LShift,
+        _ => return None,
+    };
+    Some(key)
+}
+
+// Settings for an unknown ROM, from the instructions it uses. Only reachable code is
+// looked at, since sprite data is full of what would be SUPER-CHIP instructions.
+fn guess(rom: &[u8]) -> Settings {
+    let code = reachable_code(rom);
+    let nibbles = |insn: u16| (insn >> 12, (insn >> 8) & 0xF, (insn >> 4) & 0xF, insn & 0xF);
+    // Only instructions this interpreter emulates count, as a program that needs others
+    // would not run anyway.
+    let xo_chip = rom.len() > Platform::Schip.max_rom_size()
+        || code.iter().any(|&insn| {
+            matches!(
+                nibbles(insn),
+                // Audio pattern, pitch and planes.
+                (0xF, 0, 0, 2) | (0xF, _, 3, 0xA) | (0xF, _, 0, 1)
+            )
+        });
+    // Display modes.
+    let schip = code.iter().any(|&insn| matches!(insn, 0x00FE | 0x00FF));
+    let id = if xo_chip {
+        "xochip"
+    } else if schip {
+        "superchip"
+    } else {
+        return Settings::default();
+    };
+    RomEntry {
+        platforms: vec![id.into()],
+        ..RomEntry::default()
+    }
+    .settings()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_platform_from_code() {
+        // HIRES, then loop.
+        let schip = guess(&[0x00, 0xFF, 0x12, 0x02]);
+        assert_eq!(schip.platform, Some(Platform::Schip));
+        assert_eq!(schip.cycles_per_frame, Some(30));
+        // Select both planes, then loop.
+        let xo_chip = guess(&[0xF3, 0x01, 0x12, 0x02]);
+        assert_eq!(xo_chip.platform, Some(Platform::XoChip));
+        assert_eq!(xo_chip.quirks.map(|quirks| quirks.wrap), Some(true));
+        let mut large = vec![0x12, 0x00];
+        large.resize(Platform::Schip.max_rom_size() + 1, 0);
+        assert_eq!(guess(&large).platform, Some(Platform::XoChip));
+    }
+
+    #[test]
+    fn ignores_data_and_unemulated_instructions() {
+        // Loop, followed by data that would be HIRES.
+        assert_eq!(guess(&[0x12, 0x00, 0x00, 0xFF]).platform, None);
+        // SCHIP's 00FD exit is not emulated.
+        assert_eq!(guess(&[0x00, 0xFD, 0x12, 0x02]).platform, None);
+    }
+
+    #[test]
+    fn bundled_database_is_valid() {
+        let database = Database::bundled();
+        for program in &database.programs {
+            for hash in program.roms.keys() {
+                assert_eq!(hash.len(), 40, "{}", program.title);
+                assert!(
+                    hash.bytes().all(|c| c.is_ascii_hexdigit()),
+                    "{}",
+                    program.title
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn detects_known_roms() {
+        let rom = [0x12, 0x00];
+        let json = format!(
+            r#"[{{
+                "title": "Loop",
+                "authors": ["A", "B", "C"],
+                "roms": {{"{}": {{
+                    "platforms": ["megachip8", "superchip"],
+                    "quirkyPlatforms": {{"superchip": {{"jump": false}}}},
+                    "tickrate": 20,
+                    "keys": {{"left": 4, "a": 5, "up": 16}}
+                }}}}
+            }}]"#,
+            rom_hash(&rom)
+        );
+        let database: Database = serde_json::from_str(&json).unwrap();
+        let detected = database.detect(&rom);
+        assert_eq!(detected.title.as_deref(), Some("Loop by A, B and C"));
+        let settings = detected.settings;
+        assert_eq!(settings.platform, Some(Platform::Schip));
+        assert_eq!(settings.cycles_per_frame, Some(20));
+        let quirks = settings.quirks.unwrap();
+        assert!(quirks.shift && quirks.memory_leave_i_unchanged && !quirks.jump);
+        assert_eq!(settings.keys.get(&VirtualKeyCode::Left), Some(&4));
+        assert_eq!(settings.keys.get(&VirtualKeyCode::Space), Some(&5));
+        assert_eq!(settings.keys.len(), 2);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::chip8::constants::*;
+use crate::chip8::{Display, DrawInfo, Interpreter, Registers};
+use crate::config::Settings;
+use crate::input::{InputOptions, KeyInput};
+use crate::movie::{Movie, MovieMode, MoviePlayer, MovieRecorder};
+use crate::recorder::Recorder;
+use log::{error, info};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "recompiled")]
+use crate::chip8::Quirks;
+#[cfg(feature = "recompiled")]
+use crate::game;
+
+// Runs the interpreter on its own thread, so that window events (resizing, dragging)
+// never stall emulation and emulation speed never affects window responsiveness.
+// The frontend sends input over a channel; the emulator publishes the display of every
+// completed frame into a shared buffer and notifies the frontend to present it.
+//
+// The emulator can also record the keypad changes into an input movie, or play one back
+// in place of the frontend's input.
+
+#[derive(Debug)]
+pub enum EmulatorCommand {
+    KeyDown(u32),
+    KeyUp(u32),
+    StartRecording(PathBuf, Box<Settings>),
+    StopRecording,
+    SetPaused(bool),
+    SetFastForward(bool),
+    Quit,
+}
+
+// Events sent from the emulator thread to the frontend.
+#[derive(Debug)]
+pub enum EmulatorEvent {
+    FrameReady,
+    // Movie playback is over, with the result of checking the final state.
+    MovieFinished(Result<(), String>),
+    Exited,
+}
+
+// Speed multiplier while fast-forwarding.
+const FAST_FORWARD_SPEED: u32 = 4;
+
+// State of the emulator as of the last published frame, for on-screen displays.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Status {
+    pub registers: Registers,
+    // Instructions executed so far, wrapping around.
+    pub cycles: u32,
+    // Nominal speed, in instructions per second.
+    pub hz: u32,
+    pub paused: bool,
+    pub fast_forward: bool,
+    pub last_draw: Option<DrawInfo>,
+    // Keys held down.
+    pub keypad: [bool; 16],
+    pub beeping: bool,
+}
+
+struct Shared {
+    display: Mutex<Display>,
+    memory: Mutex<Vec<u8>>,
+    status: Mutex<Status>,
+}
+
+pub struct Emulator {
+    commands: Sender<EmulatorCommand>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Emulator {
+    // notify is called from the emulator thread, and returns false once the frontend
+    // is gone.
+    pub fn spawn<F>(
+        rom: Vec<u8>,
+        settings: &Settings,
+        movie: Option<MovieMode>,
+        notify: F,
+    ) -> Emulator
+    where
+        F: Fn(EmulatorEvent) -> bool + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            display: Mutex::new(Display::new(CHIP8_WIDTH, CHIP8_HEIGHT)),
+            memory: Mutex::new(vec![]),
+            status: Mutex::new(Status::default()),
+        });
+        let thread_shared = shared.clone();
+        let tone = settings.tone();
+        let input = InputOptions::from_settings(settings);
+        let platform = settings.platform.unwrap_or_default();
+        #[cfg(not(feature = "recompiled"))]
+        let quirks = settings.quirks.unwrap_or_default();
+        // Recompiled code performs shifts and logic inline, as the default quirks say.
+        #[cfg(feature = "recompiled")]
+        let quirks = Quirks::default();
+        let hz = settings.hz();
+        let thread = thread::Builder::new()
+            .name("emulator".into())
+            .spawn(move || {
+                let (seed, platform, quirks, hz) = match &movie {
+                    Some(MovieMode::Play(movie)) => {
+                        (movie.seed, movie.platform, movie.quirks, movie.hz)
+                    }
+                    _ => (rand::random(), platform, quirks, hz),
+                };
+                // Created on this thread, since the audio output stream cannot be moved.
+                let chip8 = Interpreter::new()
+                    .with_platform(platform)
+                    .with_quirks(quirks)
+                    .with_hz(hz)
+                    .with_sound(tone)
+                    .with_throttling(true)
+                    .with_seed(seed as u64)
+                    .load_rom(&rom);
+                let chip8 = match chip8 {
+                    Ok(chip8) => chip8,
+                    Err(e) => {
+                        error!("Could not load ROM: {}", e);
+                        notify(EmulatorEvent::Exited);
+                        return;
+                    }
+                };
+                let (recorder, player) = match movie {
+                    Some(MovieMode::Play(movie)) => (None, Some(MoviePlayer::new(movie))),
+                    Some(MovieMode::Record(path)) => {
+                        let movie = Movie::new(&rom, seed, &chip8);
+                        (Some(MovieRecorder::new(path, movie)), None)
+                    }
+                    None => (None, None),
+                };
+                let input = KeyInput::new(input, chip8.hz());
+                run(
+                    chip8,
+                    receiver,
+                    &thread_shared,
+                    &notify,
+                    input,
+                    recorder,
+                    player,
+                );
+                notify(EmulatorEvent::Exited);
+            })
+            .expect("Could not spawn emulator thread");
+        Emulator {
+            commands,
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&self, command: EmulatorCommand) {
+        // The thread only hangs up after Quit, or if it panicked. Either way, the
+        // frontend is notified through EmulatorEvent::Exited.
+        let _ = self.commands.send(command);
+    }
+
+    // The display as of the last frame published by the emulator.
+    pub fn display(&self) -> MutexGuard<'_, Display> {
+        self.shared.display.lock().unwrap()
+    }
+
+    // Memory as of the last published frame.
+    pub fn memory(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.shared.memory.lock().unwrap()
+    }
+
+    pub fn status(&self) -> Status {
+        *self.shared.status.lock().unwrap()
+    }
+
+    pub fn stop(&mut self) {
+        self.send(EmulatorCommand::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(
+    mut chip8: Interpreter,
+    commands: Receiver<EmulatorCommand>,
+    shared: &Shared,
+    notify: &dyn Fn(EmulatorEvent) -> bool,
+    mut input: KeyInput,
+    mut movie_recorder: Option<MovieRecorder>,
+    mut movie_player: Option<MoviePlayer>,
+) {
+    let mut last_frame = chip8.frame_count();
+    let mut recorder: Option<Recorder> = None;
+    let mut paused = false;
+    let mut fast_forward = false;
+    // Cycles executed so far, which movie events are timed by.
+    let mut cycle: u64 = 0;
+    'run: loop {
+        loop {
+            // While paused, block until there is something to do.
+            let command = if paused {
+                commands.recv().map_err(|_| TryRecvError::Disconnected)
+            } else {
+                commands.try_recv()
+            };
+            match command {
+                // Input comes from the movie while playing one back.
+                Ok(EmulatorCommand::KeyDown(_) | EmulatorCommand::KeyUp(_))
+                    if movie_player.is_some() => {}
+                Ok(EmulatorCommand::KeyDown(key)) => {
+                    input.key(key as u8, true, cycle, &mut |key, pressed| {
+                        set_key(&mut chip8, movie_recorder.as_mut(), cycle, key, pressed)
+                    });
+                }
+                Ok(EmulatorCommand::KeyUp(key)) => {
+                    input.key(key as u8, false, cycle, &mut |key, pressed| {
+                        set_key(&mut chip8, movie_recorder.as_mut(), cycle, key, pressed)
+                    });
+                }
+                Ok(EmulatorCommand::StartRecording(path, settings)) => {
+                    finish_recording(recorder.take());
+                    recorder = Recorder::start(&path, &settings)
+                        .map_err(|e| error!("Could not record to {}: {}", path.display(), e))
+                        .ok();
+                }
+                Ok(EmulatorCommand::StopRecording) => finish_recording(recorder.take()),
+                Ok(EmulatorCommand::SetPaused(pause)) => {
+                    paused = pause;
+                    chip8.mute();
+                    publish(&chip8, shared, paused, fast_forward);
+                    // Let the frontend show the new state, even though no frame was run.
+                    if !notify(EmulatorEvent::FrameReady) {
+                        break 'run;
+                    }
+                }
+                Ok(EmulatorCommand::SetFastForward(enable)) if enable != fast_forward => {
+                    fast_forward = enable;
+                    chip8.set_speed(if enable { FAST_FORWARD_SPEED } else { 1 });
+                }
+                Ok(EmulatorCommand::SetFastForward(_)) => (),
+                Ok(EmulatorCommand::Quit) | Err(TryRecvError::Disconnected) => {
+                    info!("Stopping emulator");
+                    break 'run;
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        if movie_player.is_none() {
+            input.tick(cycle, &mut |key, pressed| {
+                set_key(&mut chip8, movie_recorder.as_mut(), cycle, key, pressed)
+            });
+        }
+        if let Some(player) = movie_player.as_mut() {
+            if !player.play(cycle, &mut chip8) {
+                let result = player.movie().verify(&chip8);
+                match &result {
+                    Ok(()) => info!("Movie finished after {} cycles", cycle),
+                    Err(e) => error!("Movie playback diverged: {}", e),
+                }
+                movie_player = None;
+                if !notify(EmulatorEvent::MovieFinished(result)) {
+                    break 'run;
+                }
+            }
+        }
+
+        let cycle_count = chip8.cycle_count;
+        #[cfg(feature = "recompiled")]
+        if !game::run_block(&mut chip8) {
+            chip8.step();
+        }
+        #[cfg(not(feature = "recompiled"))]
+        chip8.step();
+        cycle += chip8.cycle_count.wrapping_sub(cycle_count) as u64;
+
+        if chip8.frame_count() != last_frame {
+            if let Some(rec) = recorder.as_mut() {
+                // Record a frame for every tick, even if a step spanned several.
+                for _ in last_frame..chip8.frame_count() {
+                    if let Err(e) =
+                        rec.add_frame(chip8.display(), chip8.is_beeping(), chip8.pattern())
+                    {
+                        error!("Recording failed: {}", e);
+                        finish_recording(recorder.take());
+                        break;
+                    }
+                }
+            }
+            last_frame = chip8.frame_count();
+            publish(&chip8, shared, paused, fast_forward);
+            if !notify(EmulatorEvent::FrameReady) {
+                // The frontend is gone.
+                break 'run;
+            }
+        }
+    }
+    finish_recording(recorder);
+    if let Some(movie) = movie_recorder {
+        match movie.finish(cycle, &chip8) {
+            Ok(path) => info!("Saved movie {}", path.display()),
+            Err(e) => error!("Could not save movie: {}", e),
+        }
+    }
+}
+
+fn publish(chip8: &Interpreter, shared: &Shared, paused: bool, fast_forward: bool) {
+    shared.display.lock().unwrap().clone_from(chip8.display());
+    let mut memory = shared.memory.lock().unwrap();
+    memory.clear();
+    memory.extend_from_slice(chip8.memory());
+    drop(memory);
+    *shared.status.lock().unwrap() = Status {
+        registers: chip8.registers(),
+        cycles: chip8.cycle_count,
+        hz: chip8.hz(),
+        paused,
+        fast_forward,
+        last_draw: chip8.last_draw(),
+        keypad: *chip8.keypad(),
+        beeping: chip8.is_beeping(),
+    };
+}
+
+fn finish_recording(recorder: Option<Recorder>) {
+    if let Some(recorder) = recorder {
+        if let Err(e) = recorder.finish() {
+            error!("Could not finish recording: {}", e);
+        }
+    }
+}
+
+// Pass a key event from the frontend on to the interpreter, and the movie being recorded.
+fn set_key(
+    chip8: &mut Interpreter,
+    movie: Option<&mut MovieRecorder>,
+    cycle: u64,
+    key: u8,
+    pressed: bool,
+) {
+    if pressed {
+        chip8.press_key(key);
+    } else {
+        chip8.release_key(key);
+    }
+    if let Some(movie) = movie {
+        movie.key(cycle, key, pressed);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::Settings;
+
+// Accessibility options for the keypad, applied on the emulator thread to the keys
+// pressed and released by the frontend, before the interpreter sees them:
+//
+// - Sticky keys stay held after being released, until another key is pressed, or the
+//   same key is pressed again. Games can then be played one key at a time.
+// - Toggle keys are held by one press and released by the next, for keys that games
+//   expect to be held for long.
+// - Key repeat releases and presses a held key again at regular intervals, for games
+//   that wait for a key with FX0A and only move a step per press.
+//
+// Timings are in emulated cycles rather than wall clock time, so that the resulting
+// key events end up in input movies like any other.
+
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    pub sticky: bool,
+    pub toggle: [bool; 16],
+    // Delay before the first repeat and interval between repeats, in milliseconds.
+    pub repeat: Option<(u32, u32)>,
+}
+
+impl InputOptions {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut toggle = [false; 16];
+        for &key in settings.toggle_keys.iter().flatten() {
+            if let Some(toggle) = toggle.get_mut(key as usize) {
+                *toggle = true;
+            }
+        }
+        let repeat = settings.repeat_interval.map(|interval| {
+            let interval = interval.max(1);
+            (settings.repeat_delay.unwrap_or(interval), interval)
+        });
+        InputOptions {
+            sticky: settings.sticky_keys.unwrap_or(false),
+            toggle,
+            repeat,
+        }
+    }
+}
+
+pub struct KeyInput {
+    options: InputOptions,
+    // Emulated cycles per second.
+    hz: u32,
+    // Keys held down on the host, and on the CHIP-8 keypad.
+    host: [bool; 16],
+    keypad: [bool; 16],
+    // Key to repeat, and the cycle of its next repeat.
+    repeat: Option<(u8, u64)>,
+}
+
+impl KeyInput {
+    pub fn new(options: InputOptions, hz: u32) -> Self {
+        KeyInput {
+            options,
+            hz,
+            host: [false; 16],
+            keypad: [false; 16],
+            repeat: None,
+        }
+    }
+
+    // Handle a key going down or up on the host at the given cycle, calling set_key
+    // for every resulting change of the CHIP-8 keypad.
+    pub fn key(&mut self, key: u8, down: bool, cycle: u64, set_key: &mut dyn FnMut(u8, bool)) {
+        let k = key as usize;
+        if k >= 16 || self.host[k] == down {
+            // Ignore auto-repeat from the host, which has a repeat of its own.
+            return;
+        }
+        self.host[k] = down;
+        if self.options.toggle[k] {
+            if down {
+                self.set(key, !self.keypad[k], set_key);
+            }
+        } else if self.options.sticky {
+            if down {
+                let held = self.keypad[k];
+                for other in 0..16 {
+                    if other != k && self.keypad[other] && !self.options.toggle[other] {
+                        self.set(other as u8, false, set_key);
+                    }
+                }
+                self.set(key, !held, set_key);
+            }
+        } else {
+            self.set(key, down, set_key);
+            if down {
+                self.repeat = self
+                    .options
+                    .repeat
+                    .map(|(delay, _)| (key, cycle + self.cycles(delay)));
+            } else if self.repeat.is_some_and(|(repeated, _)| repeated == key) {
+                self.repeat = None;
+            }
+        }
+    }
+
+    // Repeat the held key, if it is time to.
+    pub fn tick(&mut self, cycle: u64, set_key: &mut dyn FnMut(u8, bool)) {
+        let (Some((key, at)), Some((_, interval))) = (self.repeat, self.options.repeat) else {
+            return;
+        };
+        if cycle >= at {
+            set_key(key, false);
+            set_key(key, true);
+            self.repeat = Some((key, cycle + self.cycles(interval)));
+        }
+    }
+
+    // Cycles in the given number of milliseconds, at least one.
+    fn cycles(&self, ms: u32) -> u64 {
+        (ms as u64 * self.hz as u64 / 1000).max(1)
+    }
+
+    fn set(&mut self, key: u8, down: bool, set_key: &mut dyn FnMut(u8, bool)) {
+        self.keypad[key as usize] = down;
+        set_key(key, down);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeat_deadlines_follow_speed() {
+        let options = InputOptions {
+            repeat: Some((500, 100)),
+            ..InputOptions::default()
+        };
+        // 1500Hz is not a whole number of cycles per millisecond.
+        let mut input = KeyInput::new(options, 1500);
+        let mut events = Vec::new();
+        input.key(4, true, 0, &mut |key, down| events.push((key, down)));
+        assert_eq!(input.repeat, Some((4, 750)));
+        input.tick(749, &mut |key, down| events.push((key, down)));
+        assert_eq!(events, [(4, true)]);
+        input.tick(750, &mut |key, down| events.push((key, down)));
+        assert_eq!(events, [(4, true), (4, false), (4, true)]);
+        assert_eq!(input.repeat, Some((4, 900)));
+    }
+}
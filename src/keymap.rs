@@ -0,0 +1,248 @@
+use crate::chip8::constants::*;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashMap;
+use winit::event::VirtualKeyCode;
+
+// Maps host keys to the CHIP-8 keypad by physical position, so that the key grid is the
+// same on QWERTY, AZERTY, Dvorak and other layouts. Layout presets are defined in terms
+// of the keys at those positions on a US keyboard, and translated to the platform's
+// scancodes. Where the scancodes are not known, keys are mapped by the character they
+// produce instead. Mappings from the config file always go by character.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    // The 4x4 block at 1234/QWER/ASDF/ZXCV, shaped like the COSMAC VIP keypad.
+    #[default]
+    Grid,
+    // Numpad digits for 0-9, and / * - + Enter . for A-F.
+    Numpad,
+    // The digit keys for 0-9 and the letter keys for A-F.
+    Hex,
+}
+
+impl Layout {
+    // The preset, by the US keyboard keys at the mapped positions.
+    fn keys(self) -> Vec<(VirtualKeyCode, u32)> {
+        use VirtualKeyCode::*;
+        match self {
+            Layout::Grid => CHIP8_KEYBOARD_MAP.to_vec(),
+            Layout::Numpad => vec![
+                (Numpad0, 0x0),
+                (Numpad1, 0x1),
+                (Numpad2, 0x2),
+                (Numpad3, 0x3),
+                (Numpad4, 0x4),
+                (Numpad5, 0x5),
+                (Numpad6, 0x6),
+                (Numpad7, 0x7),
+                (Numpad8, 0x8),
+                (Numpad9, 0x9),
+                (NumpadDivide, 0xA),
+                (NumpadMultiply, 0xB),
+                (NumpadSubtract, 0xC),
+                (NumpadAdd, 0xD),
+                (NumpadEnter, 0xE),
+                (NumpadDecimal, 0xF),
+            ],
+            Layout::Hex => vec![
+                (Key0, 0x0),
+                (Key1, 0x1),
+                (Key2, 0x2),
+                (Key3, 0x3),
+                (Key4, 0x4),
+                (Key5, 0x5),
+                (Key6, 0x6),
+                (Key7, 0x7),
+                (Key8, 0x8),
+                (Key9, 0x9),
+                (A, 0xA),
+                (B, 0xB),
+                (C, 0xC),
+                (D, 0xD),
+                (E, 0xE),
+                (F, 0xF),
+            ],
+        }
+    }
+}
+
+pub struct KeyboardMap {
+    by_scancode: HashMap<u32, u32>,
+    by_keycode: HashMap<VirtualKeyCode, u32>,
+    // Config file mappings, which take precedence.
+    overrides: HashMap<VirtualKeyCode, u32>,
+}
+
+impl KeyboardMap {
+    pub fn new(layout: Layout, overrides: HashMap<VirtualKeyCode, u32>) -> Self {
+        let keys = layout.keys();
+        let by_scancode: Option<HashMap<u32, u32>> = keys
+            .iter()
+            .map(|&(keycode, key)| Some((us_scancode(keycode)?, key)))
+            .collect();
+        KeyboardMap {
+            by_scancode: by_scancode.unwrap_or_default(),
+            by_keycode: keys.into_iter().collect(),
+            overrides,
+        }
+    }
+
+    // The CHIP-8 key for a host key, given its scancode and the key it produces, if any.
+    pub fn key(&self, scancode: u32, keycode: Option<VirtualKeyCode>) -> Option<u32> {
+        if let Some(&key) = keycode.and_then(|keycode| self.overrides.get(&keycode)) {
+            return Some(key);
+        }
+        if self.by_scancode.is_empty() {
+            keycode.and_then(|keycode| self.by_keycode.get(&keycode).copied())
+        } else {
+            self.by_scancode.get(&scancode).copied()
+        }
+    }
+
+    // All mappings by the key produced, for frontends without access to scancodes.
+    pub fn by_keycode(&self) -> HashMap<VirtualKeyCode, u32> {
+        let mut map = self.by_keycode.clone();
+        map.extend(&self.overrides);
+        map
+    }
+}
+
+// Scancode of a key on a US keyboard, as reported by winit on this platform. Linux
+// (evdev) and Windows (set 1) scancodes agree on these keys, apart from the extended
+// Windows scancodes, which winit prefixes with 0xe0.
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+fn us_scancode(keycode: VirtualKeyCode) -> Option<u32> {
+    use VirtualKeyCode::*;
+    let scancode = match keycode {
+        Key1 => 2,
+        Key2 => 3,
+        Key3 => 4,
+        Key4 => 5,
+        Key5 => 6,
+        Key6 => 7,
+        Key7 => 8,
+        Key8 => 9,
+        Key9 => 10,
+        Key0 => 11,
+        Q => 16,
+        W => 17,
+        E => 18,
+        R => 19,
+        T => 20,
+        Y => 21,
+        U => 22,
+        I => 23,
+        O => 24,
+        P => 25,
+        A => 30,
+        S => 31,
+        D => 32,
+        F => 33,
+        G => 34,
+        H => 35,
+        J => 36,
+        K => 37,
+        L => 38,
+        Z => 44,
+        X => 45,
+        C => 46,
+        V => 47,
+        B => 48,
+        N => 49,
+        M => 50,
+        NumpadMultiply => 55,
+        Numpad7 => 71,
+        Numpad8 => 72,
+        Numpad9 => 73,
+        NumpadSubtract => 74,
+        Numpad4 => 75,
+        Numpad5 => 76,
+        Numpad6 => 77,
+        NumpadAdd => 78,
+        Numpad1 => 79,
+        Numpad2 => 80,
+        Numpad3 => 81,
+        Numpad0 => 82,
+        NumpadDecimal => 83,
+        #[cfg(target_os = "linux")]
+        NumpadEnter => 96,
+        #[cfg(target_os = "linux")]
+        NumpadDivide => 98,
+        #[cfg(target_os = "windows")]
+        NumpadEnter => 0xe01c,
+        #[cfg(target_os = "windows")]
+        NumpadDivide => 0xe035,
+        _ => return None,
+    };
+    Some(scancode)
+}
+
+// macOS virtual key codes, which identify physical keys despite the name.
+#[cfg(target_os = "macos")]
+fn us_scancode(keycode: VirtualKeyCode) -> Option<u32> {
+    use VirtualKeyCode::*;
+    let scancode = match keycode {
+        A => 0x00,
+        S => 0x01,
+        D => 0x02,
+        F => 0x03,
+        H => 0x04,
+        G => 0x05,
+        Z => 0x06,
+        X => 0x07,
+        C => 0x08,
+        V => 0x09,
+        B => 0x0b,
+        Q => 0x0c,
+        W => 0x0d,
+        E => 0x0e,
+        R => 0x0f,
+        Y => 0x10,
+        T => 0x11,
+        Key1 => 0x12,
+        Key2 => 0x13,
+        Key3 => 0x14,
+        Key4 => 0x15,
+        Key6 => 0x16,
+        Key5 => 0x17,
+        Key9 => 0x19,
+        Key7 => 0x1a,
+        Key8 => 0x1c,
+        Key0 => 0x1d,
+        O => 0x1f,
+        U => 0x20,
+        I => 0x22,
+        P => 0x23,
+        L => 0x25,
+        J => 0x26,
+        K => 0x28,
+        N => 0x2d,
+        M => 0x2e,
+        NumpadDecimal => 0x41,
+        NumpadMultiply => 0x43,
+        NumpadAdd => 0x45,
+        NumpadDivide => 0x4b,
+        NumpadEnter => 0x4c,
+        NumpadSubtract => 0x4e,
+        Numpad0 => 0x52,
+        Numpad1 => 0x53,
+        Numpad2 => 0x54,
+        Numpad3 => 0x55,
+        Numpad4 => 0x56,
+        Numpad5 => 0x57,
+        Numpad6 => 0x58,
+        Numpad7 => 0x59,
+        Numpad8 => 0x5b,
+        Numpad9 => 0x5c,
+        _ => return None,
+    };
+    Some(scancode)
+}
+
+// Scancodes are unknown elsewhere, so keys are mapped by the character they produce.
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+fn us_scancode(_keycode: VirtualKeyCode) -> Option<u32> {
+    None
+}
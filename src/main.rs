@@ -1,107 +1,513 @@
+mod bench;
 mod chip8;
+mod config;
+mod database;
+mod emulator;
+mod filter;
+#[cfg(feature = "recompiled")]
+#[path = "../recompiled/game.rs"]
+#[rustfmt::skip]
+mod game;
+mod input;
+mod keymap;
+mod movie;
+mod osd;
+mod recompiler;
+mod recorder;
+mod render;
+mod scaling;
+mod screenshot;
+mod terminal;
+mod viewer;
 use chip8::constants::*;
-use chip8::Interpreter;
+use chip8::rom;
+use config::Config;
+use database::Database;
+use emulator::{Emulator, EmulatorCommand, EmulatorEvent};
+use movie::{Movie, MovieMode};
+use osd::Osd;
+use render::{Palette, Renderer};
+use scaling::{Scaler, ScalingMode};
+use std::path::Path;
+use viewer::Viewer;
 use winit::event::VirtualKeyCode;
 
-use clap::Parser;
-use log::{debug, info};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{debug, error, info};
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::{
     dpi::LogicalSize,
-    event::{ElementState, Event, WindowEvent},
-    event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    event::{ElementState, Event, MouseButton, StartCause, WindowEvent},
+    event_loop::{ControlFlow, EventLoopBuilder},
+    window::{Fullscreen, WindowBuilder, WindowId},
 };
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(long)]
-    binary: String,
-    #[arg(long, default_value_t = 2)]
+    // Recompiled builds embed their ROM, so --binary is only needed otherwise.
+    #[arg(long, required = !cfg!(feature = "recompiled"))]
+    binary: Option<String>,
+    /// Initial window size, as a multiple of the CHIP-8 resolution.
+    #[arg(long, default_value_t = CHIP8_WIN_SCALING)]
     scale: u32,
+    /// Start in fullscreen mode. F11 toggles fullscreen.
+    #[arg(long)]
+    fullscreen: bool,
+    /// Show the on-screen keypad, which can be clicked. F4 toggles it.
+    #[arg(long)]
+    keypad: bool,
+    /// How the display is fitted to the window. F10 switches between modes.
+    #[arg(long, value_enum, default_value_t = ScalingMode::Integer)]
+    scaling: ScalingMode,
+    /// Record gameplay to this file: an animation for .png/.apng/.gif files, or a raw
+    /// RGBA stream plus a .wav file for .rgba/.raw files. F9 toggles recording.
+    #[arg(long)]
+    record: Option<String>,
+    /// Record the keypad into an input movie, which can be played back with
+    /// --play-movie or the replay command.
+    #[arg(long, conflicts_with = "play_movie")]
+    record_movie: Option<String>,
+    /// Play back an input movie instead of reading the keyboard.
+    #[arg(long)]
+    play_movie: Option<String>,
+    /// Integer scaling factor for screenshots taken with F12.
+    #[arg(long, default_value_t = 1)]
+    screenshot_scale: u32,
+    #[arg(long, value_enum, default_value_t = Frontend::Window)]
+    frontend: Frontend,
+    /// Characters used to draw the display with the terminal frontend.
+    #[arg(long, value_enum, default_value_t = terminal::Glyphs::HalfBlock)]
+    glyphs: terminal::Glyphs,
+    /// Config file with global and per-ROM settings.
+    #[arg(long, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: String,
+    /// ROM database to detect settings from, in the format of the CHIP-8 database's
+    /// programs.json, instead of the bundled one.
+    #[arg(long)]
+    database: Option<String>,
+    #[command(flatten)]
+    settings: config::Settings,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Frontend {
+    Window,
+    Terminal,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a binary headless and unthrottled, and report interpreter performance.
+    Bench(Box<bench::BenchArgs>),
+    /// Recompile a binary ahead-of-time into Rust source, to be built with the
+    /// `recompiled` feature.
+    Recompile(recompiler::RecompileArgs),
+    /// Run a binary headless for a number of cycles and save the screen as a PNG.
+    Screenshot(Box<screenshot::ScreenshotArgs>),
+    /// Play back an input movie headless and unthrottled, and check its final state.
+    Replay(movie::ReplayArgs),
 }
 
 fn main() -> Result<(), Error> {
     env_logger::init();
     let args = Args::parse();
-    let binary = args.binary.as_str();
+    match &args.command {
+        Some(Command::Bench(bench_args)) => {
+            bench::run(bench_args)
+                .unwrap_or_else(|e| fail(format!("Could not run {}: {}", bench_args.binary, e)));
+            return Ok(());
+        }
+        Some(Command::Recompile(recompile_args)) => {
+            recompiler::run(recompile_args).unwrap_or_else(|e| {
+                fail(format!(
+                    "Could not recompile {}: {}",
+                    recompile_args.binary, e
+                ))
+            });
+            return Ok(());
+        }
+        Some(Command::Screenshot(screenshot_args)) => {
+            screenshot::run(screenshot_args).unwrap_or_else(|e| {
+                fail(format!(
+                    "Could not take screenshot of {}: {}",
+                    screenshot_args.binary, e
+                ))
+            });
+            return Ok(());
+        }
+        Some(Command::Replay(replay_args)) => {
+            movie::run(replay_args)
+                .unwrap_or_else(|e| fail(format!("Could not replay {}: {}", replay_args.movie, e)));
+            return Ok(());
+        }
+        None => (),
+    }
+    let binary = args.binary.as_deref().unwrap_or("<embedded>");
     let scale = args.scale;
     info!(
         "Starting Chip8 interpreter with binary {} and scale {}",
         binary, scale
     );
 
-    let event_loop = EventLoop::new();
+    #[cfg(feature = "recompiled")]
+    let rom = game::ROM.to_vec();
+    #[cfg(not(feature = "recompiled"))]
+    let rom =
+        std::fs::read(binary).unwrap_or_else(|e| fail(format!("Could not load {}: {}", binary, e)));
+    let config = Config::load(Path::new(&args.config))
+        .unwrap_or_else(|e| fail(format!("Could not load config {}: {}", args.config, e)));
+    let database = match &args.database {
+        Some(path) => Database::load(Path::new(path))
+            .unwrap_or_else(|e| fail(format!("Could not load database {}: {}", path, e))),
+        None => Database::bundled(),
+    };
+    let detected = database.detect(&rom);
+    if let Some(title) = &detected.title {
+        info!("Detected {}", title);
+    }
+    let mut settings = detected.settings;
+    settings.merge(&config.settings_for(binary, &rom));
+    settings.merge(&args.settings);
+    let movie = match (&args.record_movie, &args.play_movie) {
+        (Some(path), _) => Some(MovieMode::Record(path.into())),
+        (_, Some(path)) => {
+            let movie = Movie::load(Path::new(path), &rom)
+                .unwrap_or_else(|e| fail(format!("Could not load movie {}: {}", path, e)));
+            // Recompiled code only runs with the default quirks.
+            #[cfg(feature = "recompiled")]
+            if movie.quirks != chip8::Quirks::default() {
+                fail(format!(
+                    "Could not play movie {}: it needs quirks that recompiled builds do not support",
+                    path
+                ));
+            }
+            // Play back on the platform the movie was recorded on.
+            settings.platform = Some(movie.platform);
+            Some(MovieMode::Play(movie))
+        }
+        _ => None,
+    };
+    rom::validate(&rom, settings.platform.unwrap_or_default())
+        .unwrap_or_else(|e| fail(format!("Could not load {}: {}", binary, e)));
+    if let Some(path) = &args.record {
+        recorder::format(Path::new(path))
+            .unwrap_or_else(|e| fail(format!("Could not record to {}: {}", path, e)));
+    }
+    let mut renderer = Renderer::from_settings(&settings);
+    let palette = Palette::from_settings(&settings);
+
+    if args.frontend == Frontend::Terminal {
+        terminal::run(rom, renderer, &settings, args.glyphs, movie)
+            .unwrap_or_else(|e| fail(format!("Terminal frontend failed: {}", e)));
+        return Ok(());
+    }
+
+    let event_loop = EventLoopBuilder::<EmulatorEvent>::with_user_event().build();
     let window = {
         let size = LogicalSize::new(
-            (CHIP8_WIDTH * CHIP8_WIN_SCALING_WIDTH) as f64,
-            (CHIP8_HEIGHT * CHIP8_WIN_SCALING_HEIGHT) as f64,
+            (CHIP8_WIDTH as u32 * scale.max(1)) as f64,
+            (CHIP8_HEIGHT as u32 * scale.max(1)) as f64,
         );
+        let min_size = LogicalSize::new(CHIP8_WIDTH as f64, CHIP8_HEIGHT as f64);
         WindowBuilder::new()
-            .with_title("Chip8")
+            .with_title(match &detected.title {
+                Some(title) => format!("Chip8 - {}", title),
+                None => "Chip8".into(),
+            })
             .with_inner_size(size)
-            .with_min_inner_size(size)
+            .with_min_inner_size(min_size)
+            .with_fullscreen(args.fullscreen.then_some(Fullscreen::Borderless(None)))
             .build(&event_loop)
-            .unwrap()
+            .unwrap_or_else(|e| fail(format!("Could not create window: {}", e)))
     };
-    let pixels = {
+    let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture: SurfaceTexture<'_, winit::window::Window> =
             SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(CHIP8_WIDTH as u32, CHIP8_HEIGHT as u32, surface_texture).unwrap()
+        Pixels::new(CHIP8_WIDTH as u32, CHIP8_HEIGHT as u32, surface_texture)
+            .unwrap_or_else(|e| fail(format!("Could not create renderer: {}", e)))
     };
 
-    let mut chip8 = Interpreter::new(pixels)
-        .load_binary(binary)
-        .unwrap_or_else(|_| panic!("Could not load binary {}", binary));
+    let proxy = event_loop.create_proxy();
+    let mut emulator = Emulator::spawn(rom, &settings, movie, move |event| {
+        proxy.send_event(event).is_ok()
+    });
+    let keyboard_map = settings.keyboard_map();
+    let mut recording = false;
+    if let Some(path) = &args.record {
+        emulator.send(EmulatorCommand::StartRecording(
+            path.into(),
+            Box::new(settings.clone()),
+        ));
+        recording = true;
+    }
+    let rom_name = binary.to_owned();
+    let mut buffer_size = (CHIP8_WIDTH as u32, CHIP8_HEIGHT as u32);
+    // Size of the composed frame, which the buffer may be stretched from.
+    let mut frame_size = buffer_size;
+    let mut cursor = (0.0, 0.0);
+    // Key of the on-screen keypad held down with the mouse.
+    let mut clicked_key = None;
+    let mut osd = Osd::new();
+    if args.keypad {
+        osd.toggle_keypad();
+    }
+    let mut scaler = Scaler::new(args.scaling);
+    let window_size = window.inner_size();
+    osd.resize(window_size.width, window_size.height);
+    scaler.resize(window_size.width, window_size.height);
+    let mut paused = false;
+    let mut viewer: Option<Viewer> = None;
 
-    event_loop.run(move |event, _, control_flow| {
-        control_flow.set_poll();
+    event_loop.run(move |event, target, control_flow| {
+        match osd.next_expiry() {
+            // Redraw when a message expires, even if the emulator is paused.
+            Some(expiry) => control_flow.set_wait_until(expiry),
+            None => control_flow.set_wait(),
+        }
         match event {
+            Event::NewEvents(StartCause::ResumeTimeReached { .. }) => window.request_redraw(),
+            Event::WindowEvent { window_id, event } if Some(window_id) == viewer_id(&viewer) => {
+                let Some(sprite_viewer) = viewer.as_mut() else {
+                    return;
+                };
+                match event {
+                    WindowEvent::CloseRequested => viewer = None,
+                    WindowEvent::Resized(size) => sprite_viewer.resize(size.width, size.height),
+                    WindowEvent::KeyboardInput { input, .. }
+                        if input.state == ElementState::Pressed =>
+                    {
+                        match input.virtual_keycode {
+                            Some(VirtualKeyCode::F3 | VirtualKeyCode::Escape) => viewer = None,
+                            Some(key) => sprite_viewer.handle_key(key),
+                            None => (),
+                        }
+                    }
+                    _ => (),
+                }
+            }
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => {
                     info!("Exiting");
+                    emulator.stop();
                     *control_flow = ControlFlow::Exit;
-                    return;
                 }
                 WindowEvent::Resized(size) => {
                     debug!("Resizing window...");
-                    chip8.resize_window(size.width, size.height);
+                    pixels
+                        .resize_surface(size.width, size.height)
+                        .expect("Could not resize window");
+                    osd.resize(size.width, size.height);
+                    scaler.resize(size.width, size.height);
                     window.request_redraw();
                 }
+                WindowEvent::CursorMoved { position, .. } => {
+                    cursor = (position.x as f32, position.y as f32);
+                }
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    ..
+                } => match state {
+                    ElementState::Pressed => {
+                        let key = pixels.window_pos_to_pixel(cursor).ok().and_then(|(x, y)| {
+                            let x = x * frame_size.0 as usize / buffer_size.0 as usize;
+                            let y = y * frame_size.1 as usize / buffer_size.1 as usize;
+                            osd.keypad_key(x, y)
+                        });
+                        if let Some(key) = key {
+                            emulator.send(EmulatorCommand::KeyDown(key));
+                            clicked_key = Some(key);
+                        }
+                    }
+                    ElementState::Released => {
+                        if let Some(key) = clicked_key.take() {
+                            emulator.send(EmulatorCommand::KeyUp(key));
+                        }
+                    }
+                },
                 WindowEvent::KeyboardInput { input, .. } => {
-                    let scancode = input.virtual_keycode.expect("Invalid keycode.");
-                    if scancode == VirtualKeyCode::Escape {
+                    // Keys without a virtual keycode can still map to the keypad by
+                    // their scancode.
+                    let keycode = input.virtual_keycode;
+                    if keycode == Some(VirtualKeyCode::Escape) {
                         info!("Exiting");
+                        emulator.stop();
                         *control_flow = ControlFlow::Exit;
                         return;
                     }
-                    if chip8.keyboard_map.contains_key(&scancode) {
-                        let key = chip8.keyboard_map[&scancode];
-                        let previously_pressed = chip8.keypad[key as usize];
-                        let just_pressed = input.state == ElementState::Pressed;
-                        chip8.keypad[key as usize] = just_pressed;
-                        chip8.key_pressed = if previously_pressed && !just_pressed {
-                            Some(key)
-                        } else {
-                            None
-                        };
+                    // The keypad comes before hotkeys, which may share a key on other
+                    // keyboard layouts, such as P on Dvorak.
+                    if let Some(key) = keyboard_map.key(input.scancode, keycode) {
                         debug!(
-                            "Updating keypad {}({:?}) to {}",
-                            key, scancode, just_pressed
+                            "Key {}({:?}, scancode {}) {:?}",
+                            key, keycode, input.scancode, input.state
                         );
+                        emulator.send(match input.state {
+                            ElementState::Pressed => EmulatorCommand::KeyDown(key),
+                            ElementState::Released => EmulatorCommand::KeyUp(key),
+                        });
+                        return;
+                    }
+                    let pressed = input.state == ElementState::Pressed;
+                    match keycode {
+                        Some(VirtualKeyCode::F12) if pressed => {
+                            let path = screenshot::file_name(&rom_name, "png");
+                            match screenshot::save(
+                                &emulator.display(),
+                                &palette,
+                                args.screenshot_scale,
+                                Path::new(&path),
+                            ) {
+                                Ok(()) => {
+                                    info!("Saved screenshot {}", path);
+                                    osd.message(format!("Saved {}", path));
+                                }
+                                Err(e) => {
+                                    error!("Could not save screenshot {}: {}", path, e);
+                                    osd.message("Screenshot failed");
+                                }
+                            }
+                        }
+                        Some(VirtualKeyCode::F9) if pressed => {
+                            if recording {
+                                emulator.send(EmulatorCommand::StopRecording);
+                                osd.message("Recording stopped");
+                            } else {
+                                let path = screenshot::file_name(&rom_name, "png");
+                                emulator.send(EmulatorCommand::StartRecording(
+                                    path.into(),
+                                    Box::new(settings.clone()),
+                                ));
+                                osd.message("Recording");
+                            }
+                            recording = !recording;
+                        }
+                        Some(VirtualKeyCode::F11) if pressed => {
+                            window.set_fullscreen(match window.fullscreen() {
+                                Some(_) => None,
+                                None => Some(Fullscreen::Borderless(None)),
+                            });
+                        }
+                        Some(VirtualKeyCode::F10) if pressed => {
+                            scaler.toggle_mode();
+                            osd.message(format!("Scaling: {:?}", scaler.mode()));
+                        }
+                        Some(VirtualKeyCode::F3) if pressed => {
+                            viewer = match viewer {
+                                Some(_) => None,
+                                None => match Viewer::open(target) {
+                                    Ok(mut sprite_viewer) => {
+                                        sprite_viewer.update(
+                                            &emulator.display(),
+                                            &emulator.memory(),
+                                            emulator.status(),
+                                        );
+                                        Some(sprite_viewer)
+                                    }
+                                    Err(e) => {
+                                        error!("Could not open sprite viewer: {}", e);
+                                        osd.message("Sprite viewer failed");
+                                        None
+                                    }
+                                },
+                            };
+                        }
+                        Some(VirtualKeyCode::F1) if pressed => osd.toggle_stats(),
+                        Some(VirtualKeyCode::F2) if pressed => osd.toggle_registers(),
+                        Some(VirtualKeyCode::F4) if pressed => osd.toggle_keypad(),
+                        Some(VirtualKeyCode::P) if pressed => {
+                            paused = !paused;
+                            emulator.send(EmulatorCommand::SetPaused(paused));
+                            osd.message(if paused { "Paused" } else { "Resumed" });
+                        }
+                        // Fast-forward while the key is held.
+                        Some(VirtualKeyCode::Tab) => {
+                            emulator.send(EmulatorCommand::SetFastForward(pressed))
+                        }
+                        _ => (),
+                    }
+                    if matches!(
+                        keycode,
+                        Some(
+                            VirtualKeyCode::F1
+                                | VirtualKeyCode::F2
+                                | VirtualKeyCode::F4
+                                | VirtualKeyCode::F3
+                                | VirtualKeyCode::F9
+                                | VirtualKeyCode::F10
+                                | VirtualKeyCode::F11
+                                | VirtualKeyCode::F12
+                                | VirtualKeyCode::P
+                                | VirtualKeyCode::Tab
+                        )
+                    ) {
                         window.request_redraw();
                     }
                 }
                 _ => (),
             },
+            Event::UserEvent(EmulatorEvent::FrameReady) => {
+                renderer.set_beeping(emulator.status().beeping);
+                renderer.update(&emulator.display());
+                osd.update(emulator.status());
+                if let Some(sprite_viewer) = viewer.as_mut() {
+                    sprite_viewer.update(
+                        &emulator.display(),
+                        &emulator.memory(),
+                        emulator.status(),
+                    );
+                }
+                window.request_redraw();
+            }
+            Event::UserEvent(EmulatorEvent::MovieFinished(result)) => {
+                osd.message(match result {
+                    Ok(()) => "Movie finished",
+                    Err(_) => "Movie diverged",
+                });
+                window.request_redraw();
+            }
+            Event::UserEvent(EmulatorEvent::Exited) => {
+                info!("Emulator exited");
+                *control_flow = ControlFlow::Exit;
+            }
+            Event::RedrawRequested(window_id) if Some(window_id) == viewer_id(&viewer) => {
+                if let Some(sprite_viewer) = viewer.as_mut() {
+                    sprite_viewer.redraw();
+                }
+            }
             Event::RedrawRequested(_) => {
                 debug!("Requested redraw");
-                chip8.draw();
+                if renderer.size().0 == 0 {
+                    // Nothing emulated yet.
+                    return;
+                }
+                let (frame, size) = osd.compose(&renderer);
+                frame_size = size;
+                let (frame, (width, height)) = scaler.apply(frame, size);
+                if (width, height) != buffer_size {
+                    pixels
+                        .resize_buffer(width, height)
+                        .expect("Could not resize pixel buffer");
+                    buffer_size = (width, height);
+                }
+                pixels.frame_mut().copy_from_slice(frame);
+                pixels.render().expect("Error while rendering pixels");
             }
             _ => (),
         }
-        chip8.step();
     });
 }
+
+// Report an error on the command line, and exit.
+fn fail(message: String) -> ! {
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+fn viewer_id(viewer: &Option<Viewer>) -> Option<WindowId> {
+    viewer.as_ref().map(Viewer::id)
+}
@@ -0,0 +1,237 @@
+use crate::chip8::{Interpreter, KeyEvent, Platform, Quirks};
+use crate::config::rom_hash;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// Input movies: every keypad change made during a session, stamped with the number of
+// cycles executed before it, together with everything else the run depends on. Since
+// the interpreter is otherwise deterministic, playing a movie back reproduces the
+// session exactly, and the hash of the final state checks that it did.
+//
+// Movies are TOML files:
+//
+//   rom = "<sha1 of the ROM>"
+//   seed = 1234
+//   platform = "chip8"
+//   hz = 1000
+//   length = 52000
+//   final_state = "<sha1 of the machine state after length cycles>"
+//   events = [[1200, 5, true], [1350, 5, false]]
+//
+//   [quirks]
+//   shift = true
+//   ...
+//
+// Events are [cycle, key, pressed]. Movies are played back with the recorded platform,
+// speed and quirks, whatever the settings say.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Movie {
+    pub rom: String,
+    pub seed: u32,
+    #[serde(default)]
+    pub platform: Platform,
+    pub hz: u32,
+    #[serde(default)]
+    pub quirks: Quirks,
+    // Number of cycles in the movie.
+    pub length: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_state: Option<String>,
+    #[serde(default)]
+    pub events: Vec<MovieEvent>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MovieEvent(pub u64, pub u8, pub bool);
+
+impl Movie {
+    pub fn new(rom: &[u8], seed: u32, chip8: &Interpreter) -> Movie {
+        Movie {
+            rom: rom_hash(rom),
+            seed,
+            platform: chip8.platform(),
+            hz: chip8.hz(),
+            quirks: chip8.quirks(),
+            length: 0,
+            final_state: None,
+            events: vec![],
+        }
+    }
+
+    // Load a movie, checking that it can be played back with this ROM.
+    pub fn load(path: &Path, rom: &[u8]) -> Result<Movie, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let movie: Movie = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        if movie.rom != rom_hash(rom) {
+            return Err(format!(
+                "Movie was recorded with another ROM ({})",
+                movie.rom
+            ));
+        }
+        if let Some(MovieEvent(_, key, _)) = movie.events.iter().find(|event| event.1 > 0xF) {
+            return Err(format!("Invalid CHIP-8 key {:#x}", key));
+        }
+        Ok(movie)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = toml::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, contents).map_err(|e| e.to_string())
+    }
+
+    // Check the final state of a playback.
+    pub fn verify(&self, chip8: &Interpreter) -> Result<(), String> {
+        match &self.final_state {
+            Some(expected) if *expected != chip8.state_hash() => Err(format!(
+                "Final state {} differs from the recorded {}",
+                chip8.state_hash(),
+                expected
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// What to do with a movie while running the emulator.
+#[derive(Debug)]
+pub enum MovieMode {
+    Record(PathBuf),
+    Play(Movie),
+}
+
+// Records keypad changes from the frontend.
+pub struct MovieRecorder {
+    path: PathBuf,
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    pub fn new(path: PathBuf, movie: Movie) -> Self {
+        MovieRecorder { path, movie }
+    }
+
+    pub fn key(&mut self, cycle: u64, key: u8, pressed: bool) {
+        self.movie.events.push(MovieEvent(cycle, key, pressed));
+    }
+
+    // Save the movie, ending at the current state.
+    pub fn finish(mut self, cycle: u64, chip8: &Interpreter) -> Result<PathBuf, String> {
+        self.movie.length = cycle;
+        self.movie.final_state = Some(chip8.state_hash());
+        self.movie.save(&self.path)?;
+        Ok(self.path)
+    }
+}
+
+// Feeds the keypad changes of a movie to the interpreter.
+pub struct MoviePlayer {
+    movie: Movie,
+    next: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer { movie, next: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // Apply the events due before the given cycle. Returns false once the movie is over.
+    pub fn play(&mut self, cycle: u64, chip8: &mut Interpreter) -> bool {
+        while let Some(&MovieEvent(at, key, pressed)) = self.movie.events.get(self.next) {
+            if at > cycle {
+                break;
+            }
+            chip8.key_event(if pressed {
+                KeyEvent::Press(key)
+            } else {
+                KeyEvent::Release(key)
+            });
+            self.next += 1;
+        }
+        cycle < self.movie.length
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    #[arg(long)]
+    pub binary: String,
+    /// Movie to play back.
+    #[arg(long)]
+    pub movie: String,
+}
+
+// Play a movie back headless and unthrottled, and check its final state.
+pub fn run(args: &ReplayArgs) -> Result<(), String> {
+    let rom = std::fs::read(&args.binary).map_err(|e| e.to_string())?;
+    let movie = Movie::load(Path::new(&args.movie), &rom)?;
+    let mut chip8 = Interpreter::new()
+        .with_platform(movie.platform)
+        .with_quirks(movie.quirks)
+        .with_hz(movie.hz)
+        .with_seed(movie.seed as u64)
+        .load_rom(&rom)
+        .map_err(|e| e.to_string())?;
+    let mut player = MoviePlayer::new(movie);
+    let mut cycle = 0;
+    while player.play(cycle, &mut chip8) {
+        chip8.cycle();
+        cycle += 1;
+    }
+    player.movie().verify(&chip8)?;
+    match &player.movie().final_state {
+        Some(_) => println!("Replayed {} cycles, final state matches", cycle),
+        None => println!(
+            "Replayed {} cycles, final state {}",
+            cycle,
+            chip8.state_hash()
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_round_trip() {
+        let rom = [0x12, 0x00];
+        let chip8 = Interpreter::new()
+            .with_platform(Platform::Schip)
+            .with_quirks(Quirks {
+                jump: true,
+                ..Quirks::default()
+            })
+            .with_hz(1200);
+        let mut movie = Movie::new(&rom, 42, &chip8);
+        movie.length = 500;
+        movie.final_state = Some(chip8.state_hash());
+        movie.events = vec![MovieEvent(10, 5, true), MovieEvent(20, 5, false)];
+        let contents = toml::to_string(&movie).unwrap();
+        let loaded: Movie = toml::from_str(&contents).unwrap();
+        assert_eq!(loaded.rom, rom_hash(&rom));
+        assert_eq!(loaded.seed, 42);
+        assert_eq!(loaded.platform, Platform::Schip);
+        assert_eq!(loaded.hz, 1200);
+        assert_eq!(loaded.quirks, chip8.quirks());
+        assert_eq!(loaded.length, 500);
+        assert_eq!(loaded.final_state, movie.final_state);
+        assert_eq!(loaded.events.len(), 2);
+        assert_eq!((loaded.events[1].0, loaded.events[1].2), (20, false));
+    }
+
+    #[test]
+    fn old_movies_use_defaults() {
+        let movie: Movie =
+            toml::from_str("rom = \"x\"\nseed = 1\nhz = 600\nlength = 10\n").unwrap();
+        assert_eq!(movie.platform, Platform::Chip8);
+        assert_eq!(movie.quirks, Quirks::default());
+        assert!(movie.events.is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+use crate::emulator::Status;
+use crate::render::Renderer;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// On-screen display drawn on top of the game in the window frontend: performance
+// counters, the pause/fast-forward state, transient messages, a register panel and a
+// virtual keypad, which lights up the keys held down and can be clicked.
+//
+// The game frame is upscaled to about half the window resolution before the text is
+// drawn, so that the small bitmap font stays legible whatever the size of the display.
+// When there is nothing to show, the rendered frame is passed through untouched.
+
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+pub const LINE_HEIGHT: usize = GLYPH_HEIGHT + 1;
+const MARGIN: usize = 2;
+const TEXT_COLOR: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+const HELD_TEXT_COLOR: [u8; 4] = [0x00, 0x00, 0x00, 0xff];
+// The COSMAC VIP keypad.
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+const KEY_SIZE: usize = 9;
+const KEYPAD_SIZE: usize = 4 * (KEY_SIZE + 1) - 1;
+
+pub struct Osd {
+    // Performance counters and emulator state, toggled with F1.
+    stats: bool,
+    // Register panel, toggled with F2.
+    registers: bool,
+    // Virtual keypad, toggled with F4.
+    keypad: bool,
+    // Top left corner of the keypad in the last composed frame.
+    keypad_position: Option<(usize, usize)>,
+    messages: VecDeque<(String, Instant)>,
+    status: Status,
+    // Frames and cycles counted since the start of the current measurement.
+    measure_start: Instant,
+    measure_frames: u32,
+    measure_cycles: u32,
+    fps: f64,
+    ips: f64,
+    window_size: (u32, u32),
+    width: usize,
+    height: usize,
+    frame: Vec<u8>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd {
+            stats: false,
+            registers: false,
+            keypad: false,
+            keypad_position: None,
+            messages: VecDeque::new(),
+            status: Status::default(),
+            measure_start: Instant::now(),
+            measure_frames: 0,
+            measure_cycles: 0,
+            fps: 0.0,
+            ips: 0.0,
+            window_size: (0, 0),
+            width: 0,
+            height: 0,
+            frame: vec![],
+        }
+    }
+
+    pub fn toggle_stats(&mut self) {
+        self.stats = !self.stats;
+    }
+
+    pub fn toggle_registers(&mut self) {
+        self.registers = !self.registers;
+    }
+
+    pub fn toggle_keypad(&mut self) {
+        self.keypad = !self.keypad;
+    }
+
+    // The key of the virtual keypad at (x, y) in the last composed frame, if any.
+    pub fn keypad_key(&self, x: usize, y: usize) -> Option<u32> {
+        let (left, top) = self.keypad_position?;
+        let (dx, dy) = (x.checked_sub(left)?, y.checked_sub(top)?);
+        let (col, row) = (dx / (KEY_SIZE + 1), dy / (KEY_SIZE + 1));
+        // Not in the gaps between keys either.
+        if col >= 4
+            || row >= 4
+            || dx % (KEY_SIZE + 1) == KEY_SIZE
+            || dy % (KEY_SIZE + 1) == KEY_SIZE
+        {
+            return None;
+        }
+        Some(KEYPAD[row][col] as u32)
+    }
+
+    // Show a message for a couple of seconds.
+    pub fn message(&mut self, text: impl Into<String>) {
+        self.messages
+            .push_back((text.into(), Instant::now() + MESSAGE_DURATION));
+    }
+
+    // When the next message expires, and the display must be redrawn without it.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.messages.front().map(|(_, expiry)| *expiry)
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.window_size = (width, height);
+    }
+
+    // Called for every frame published by the emulator.
+    pub fn update(&mut self, status: Status) {
+        if self.measure_frames == 0 {
+            self.measure_cycles = status.cycles;
+        }
+        self.measure_frames += 1;
+        let elapsed = self.measure_start.elapsed();
+        if elapsed >= MEASURE_INTERVAL {
+            let seconds = elapsed.as_secs_f64();
+            self.fps = (self.measure_frames - 1) as f64 / seconds;
+            self.ips = status.cycles.wrapping_sub(self.measure_cycles) as f64 / seconds;
+            self.measure_start = Instant::now();
+            self.measure_frames = 0;
+        }
+        self.status = status;
+    }
+
+    // The rendered frame with the overlay on top, and its size.
+    pub fn compose<'a>(&'a mut self, renderer: &'a Renderer) -> (&'a [u8], (u32, u32)) {
+        let now = Instant::now();
+        while self
+            .messages
+            .front()
+            .is_some_and(|(_, expiry)| *expiry <= now)
+        {
+            self.messages.pop_front();
+        }
+        let lines = self.stats_lines();
+        let registers = self.register_lines();
+        self.keypad_position = None;
+        if lines.is_empty() && registers.is_empty() && self.messages.is_empty() && !self.keypad {
+            return (renderer.frame(), renderer.size());
+        }
+
+        self.upscale(renderer);
+        let top = MARGIN;
+        self.draw_block(&lines, MARGIN, top);
+        let register_width = registers.iter().map(|l| text_width(l)).max().unwrap_or(0);
+        let register_x = self.width.saturating_sub(register_width + MARGIN);
+        self.draw_block(&registers, register_x, top);
+        let messages: Vec<String> = self.messages.iter().map(|(m, _)| m.clone()).collect();
+        let bottom = self
+            .height
+            .saturating_sub(messages.len() * LINE_HEIGHT + MARGIN);
+        self.draw_block(&messages, MARGIN, bottom);
+        if self.keypad {
+            self.draw_keypad();
+        }
+        (&self.frame, (self.width as u32, self.height as u32))
+    }
+
+    fn stats_lines(&self) -> Vec<String> {
+        if !self.stats {
+            return vec![];
+        }
+        let speed = if self.status.hz == 0 {
+            0.0
+        } else {
+            100.0 * self.ips / self.status.hz as f64
+        };
+        let state = if self.status.paused {
+            "PAUSED"
+        } else if self.status.fast_forward {
+            "FAST FORWARD"
+        } else {
+            "RUNNING"
+        };
+        vec![
+            format!("FPS {:.0}", self.fps),
+            format!("IPS {:.0}", self.ips),
+            format!("SPEED {:.0}%", speed),
+            state.to_string(),
+        ]
+    }
+
+    fn register_lines(&self) -> Vec<String> {
+        if !self.registers {
+            return vec![];
+        }
+        let r = &self.status.registers;
+        let mut lines = vec![
+            format!("PC {:04X} I {:04X}", r.pc, r.i),
+            format!(
+                "SP {:X} DT {:02X} ST {:02X}",
+                r.sp, r.delay_timer, r.sound_timer
+            ),
+        ];
+        for (n, values) in r.v.chunks(4).enumerate() {
+            let regs: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| format!("V{:X} {:02X}", n * 4 + i, value))
+                .collect();
+            lines.push(regs.join(" "));
+        }
+        lines
+    }
+
+    // Copy the rendered frame into the overlay buffer, with nearest neighbour scaling.
+    fn upscale(&mut self, renderer: &Renderer) {
+        let (src_width, src_height) = renderer.size();
+        let (src_width, src_height) = (src_width as usize, src_height as usize);
+        let (window_width, window_height) = self.window_size;
+        let fit = (window_width as usize / src_width.max(1))
+            .min(window_height as usize / src_height.max(1));
+        let scale = (fit / 2).max(1);
+        self.width = src_width * scale;
+        self.height = src_height * scale;
+        self.frame.resize(self.width * self.height * 4, 0);
+        let src = renderer.frame();
+        for (y, row) in self.frame.chunks_exact_mut(self.width * 4).enumerate() {
+            let src_row = &src[(y / scale) * src_width * 4..][..src_width * 4];
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                pixel.copy_from_slice(&src_row[(x / scale) * 4..][..4]);
+            }
+        }
+    }
+
+    // Draw the keypad in the bottom right corner, with the keys held down lit up.
+    fn draw_keypad(&mut self) {
+        let left = self.width.saturating_sub(KEYPAD_SIZE + MARGIN);
+        let top = self.height.saturating_sub(KEYPAD_SIZE + MARGIN);
+        self.keypad_position = Some((left, top));
+        for (row, keys) in KEYPAD.iter().enumerate() {
+            for (col, &key) in keys.iter().enumerate() {
+                let x = left + col * (KEY_SIZE + 1);
+                let y = top + row * (KEY_SIZE + 1);
+                let held = self.status.keypad[key as usize];
+                for py in y..(y + KEY_SIZE).min(self.height) {
+                    for px in x..(x + KEY_SIZE).min(self.width) {
+                        let offset = (py * self.width + px) * 4;
+                        let pixel = &mut self.frame[offset..offset + 3];
+                        if held {
+                            pixel.copy_from_slice(&TEXT_COLOR[..3]);
+                        } else {
+                            // Darkened, but visible on black too.
+                            pixel.iter_mut().for_each(|c| *c = *c / 3 + 0x30);
+                        }
+                    }
+                }
+                let label = format!("{:X}", key);
+                let color = if held { HELD_TEXT_COLOR } else { TEXT_COLOR };
+                let (text_x, text_y) = (x + (KEY_SIZE - GLYPH_WIDTH) / 2, y + 2);
+                draw_text(&mut self.frame, self.width, text_x, text_y, &label, color);
+            }
+        }
+    }
+
+    // Draw lines of text on a darkened box, clipped to the frame.
+    fn draw_block(&mut self, lines: &[String], x: usize, y: usize) {
+        if lines.is_empty() {
+            return;
+        }
+        let width = lines.iter().map(|l| text_width(l)).max().unwrap_or(0);
+        let height = lines.len() * LINE_HEIGHT - 1;
+        for py in y.saturating_sub(1)..(y + height + 1).min(self.height) {
+            for px in x.saturating_sub(1)..(x + width + 1).min(self.width) {
+                let offset = (py * self.width + px) * 4;
+                for c in &mut self.frame[offset..offset + 3] {
+                    *c /= 3;
+                }
+            }
+        }
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(
+                &mut self.frame,
+                self.width,
+                x,
+                y + i * LINE_HEIGHT,
+                line,
+                TEXT_COLOR,
+            );
+        }
+    }
+}
+
+// Draw a line of text with its top left corner at (x, y), clipped to the frame. Also
+// used by the other debugging displays.
+pub fn draw_text(frame: &mut [u8], width: usize, x: usize, y: usize, text: &str, color: [u8; 4]) {
+    let height = frame.len() / 4 / width.max(1);
+    for (n, c) in text.chars().enumerate() {
+        let x = x + n * (GLYPH_WIDTH + 1);
+        for (dy, bits) in glyph(c).iter().enumerate() {
+            for dx in 0..GLYPH_WIDTH {
+                let (px, py) = (x + dx, y + dy);
+                if bits >> (GLYPH_WIDTH - 1 - dx) & 0x1 == 0 || px >= width || py >= height {
+                    continue;
+                }
+                let offset = (py * width + px) * 4;
+                frame[offset..offset + 4].copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+pub fn text_width(text: &str) -> usize {
+    (text.chars().count() * (GLYPH_WIDTH + 1)).saturating_sub(1)
+}
+
+// 3x5 pixel font, one row of 3 bits per entry. Lowercase letters are drawn in
+// uppercase, and unsupported characters as a question mark.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
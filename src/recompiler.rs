@@ -0,0 +1,334 @@
+use crate::chip8::constants::*;
+use crate::chip8::{decode, rom, Instruction, Instruction::*, Platform};
+use clap::Args;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// Ahead-of-time recompiler from CHIP-8 to Rust.
+//
+// Starting from the entry point, the recompiler follows every statically known jump,
+// call, skip and return to discover the reachable code, and splits it into basic
+// blocks. Each block becomes a Rust function in which register operations are emitted
+// inline, while everything touching the display, input, timers or control flow calls
+// back into the interpreter runtime. The generated dispatcher falls back to the
+// interpreter whenever the pc is not at a known block (e.g. after a computed JumpOff),
+// or when the ROM has overwritten the code a block was compiled from.
+//
+// The output is compiled into the binary with `cargo build --features recompiled`,
+// which produces an executable that runs the embedded ROM.
+
+#[derive(Args, Debug)]
+pub struct RecompileArgs {
+    #[arg(long)]
+    pub binary: String,
+    /// Where to write the generated Rust source.
+    #[arg(long, default_value = "recompiled/game.rs")]
+    output: String,
+}
+
+pub fn run(args: &RecompileArgs) -> std::io::Result<()> {
+    let rom = rom::read(&args.binary, Platform::default())?;
+    let source = recompile(&rom, &args.binary);
+    if let Some(dir) = std::path::Path::new(&args.output).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&args.output, source)?;
+    println!("Wrote {}", args.output);
+    Ok(())
+}
+
+struct Program<'a> {
+    rom: &'a [u8],
+    // Every reachable instruction, by address.
+    code: BTreeMap<u16, Instruction>,
+    // Addresses at which a basic block starts.
+    leaders: BTreeSet<u16>,
+}
+
+impl Program<'_> {
+    fn fetch(&self, addr: u16) -> Option<u16> {
+        let offset = (addr as usize).checked_sub(CHIP8_PROGRAM_START)?;
+        let bytes = self.rom.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&self, start: u16, end: u16) -> &[u8] {
+        let offset = start as usize - CHIP8_PROGRAM_START;
+        &self.rom[offset..offset + (end - start) as usize]
+    }
+}
+
+fn is_skip(insn: &Instruction) -> bool {
+    matches!(
+        insn,
+        SkipPressed(_)
+            | SkipNotPressed(_)
+            | SkipEq(_, _)
+            | SkipEqIm(_, _)
+            | SkipNe(_, _)
+            | SkipNeIm(_, _)
+    )
+}
+
+// Instructions after which execution does not simply continue with the next one.
+// Memory writes are included, since they may overwrite the rest of the block, and so is
+// Draw, which waits for the next frame by staying put with the vblank quirk.
+fn ends_block(insn: &Instruction) -> bool {
+    is_skip(insn)
+        || matches!(
+            insn,
+            Return
+                | Jump(_)
+                | Call(_)
+                | JumpOff(_)
+                | WaitKeypress(_)
+                | StoreRegs(_)
+                | StoreBcd(_)
+                | Draw(_, _, _)
+        )
+}
+
+fn analyze(rom: &[u8]) -> Program<'_> {
+    let mut program = Program {
+        rom,
+        code: BTreeMap::new(),
+        leaders: BTreeSet::new(),
+    };
+    let entry = CHIP8_PROGRAM_START as u16;
+    let mut worklist = vec![entry];
+    program.leaders.insert(entry);
+    while let Some(addr) = worklist.pop() {
+        if program.code.contains_key(&addr) {
+            continue;
+        }
+        let Some(raw) = program.fetch(addr) else {
+            continue;
+        };
+        let insn = decode(raw);
+        program.code.insert(addr, insn);
+        let next = addr + 2;
+        let successors: Vec<u16> = match insn {
+            Jump(target) => vec![target],
+            Call(target) => vec![target, next],
+            // Return targets are the return sites of calls, JumpOff targets are unknown.
+            Return | JumpOff(_) => vec![],
+            _ if is_skip(&insn) => vec![next, next + 2],
+            _ => vec![next],
+        };
+        for successor in successors {
+            if ends_block(&insn) {
+                program.leaders.insert(successor);
+            }
+            worklist.push(successor);
+        }
+    }
+    program
+}
+
+// The raw instructions reachable from the entry point, e.g. to tell which platform a
+// program was written for without mistaking its data for code.
+pub fn reachable_code(rom: &[u8]) -> Vec<u16> {
+    let program = analyze(rom);
+    program
+        .code
+        .keys()
+        .filter_map(|&addr| program.fetch(addr))
+        .collect()
+}
+
+fn emit_inline(insn: &Instruction) -> Option<String> {
+    let code = match *insn {
+        Nop => String::new(),
+        LoadIm(x, kk) => format!("chip.v_mut()[{x}] = {kk:#04x};"),
+        AddIm(x, kk) => {
+            format!("let v = chip.v_mut(); v[{x}] = v[{x}].wrapping_add({kk:#04x});")
+        }
+        Move(x, y) => format!("let v = chip.v_mut(); v[{x}] = v[{y}];"),
+        Or(x, y) => format!("let v = chip.v_mut(); v[{x}] |= v[{y}];"),
+        And(x, y) => format!("let v = chip.v_mut(); v[{x}] &= v[{y}];"),
+        Xor(x, y) => format!("let v = chip.v_mut(); v[{x}] ^= v[{y}];"),
+        Add(x, y) => format!(
+            "let v = chip.v_mut(); let (r, o) = v[{x}].overflowing_add(v[{y}]); v[{x}] = r; v[0xf] = o as u8;"
+        ),
+        Sub(x, y) => format!(
+            "let v = chip.v_mut(); let (r, o) = v[{x}].overflowing_sub(v[{y}]); v[{x}] = r; v[0xf] = !o as u8;"
+        ),
+        SubN(x, y) => format!(
+            "let v = chip.v_mut(); let (r, o) = v[{y}].overflowing_sub(v[{x}]); v[{x}] = r; v[0xf] = !o as u8;"
+        ),
+        // Shifts and logic follow the default quirks, which recompiled builds run with.
+        Shl(x, _) => format!(
+            "let v = chip.v_mut(); let f = (v[{x}] >> 7) & 0x1; v[{x}] <<= 1; v[0xf] = f;"
+        ),
+        Shr(x, _) => {
+            format!("let v = chip.v_mut(); let f = v[{x}] & 0x1; v[{x}] >>= 1; v[0xf] = f;")
+        }
+        LoadI(nnn) => format!("*chip.i_mut() = {nnn:#05x};"),
+        AddI(x) => format!("let vx = chip.v_mut()[{x}]; *chip.i_mut() += u16::from(vx);"),
+        _ => return None,
+    };
+    Some(code)
+}
+
+pub fn recompile(rom: &[u8], name: &str) -> String {
+    let program = analyze(rom);
+    // Only guard against self-modifying code if the ROM can write to memory at all.
+    let writes_memory = program
+        .code
+        .values()
+        .any(|insn| matches!(insn, StoreRegs(_) | StoreBcd(_)));
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "// Generated by `chip8 recompile --binary {name}`. Do not edit."
+    )
+    .unwrap();
+    writeln!(out, "#![allow(unused_variables, clippy::all)]").unwrap();
+    writeln!(out, "use crate::chip8::{{Instruction::*, Interpreter}};").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "pub const ROM: &[u8] = &{:?};", rom).unwrap();
+    writeln!(out).unwrap();
+
+    // Dispatcher
+    out.push_str("// Run the block starting at the current pc. Returns false if there is none,\n");
+    out.push_str("// in which case the interpreter must execute the next step.\n");
+    writeln!(out, "pub fn run_block(chip: &mut Interpreter) -> bool {{").unwrap();
+    writeln!(out, "    match chip.pc() {{").unwrap();
+    for leader in program
+        .leaders
+        .iter()
+        .filter(|a| program.code.contains_key(a))
+    {
+        writeln!(out, "        {leader:#05x} => block_{leader:03x}(chip),").unwrap();
+    }
+    writeln!(out, "        _ => false,").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for &leader in program.leaders.iter() {
+        if !program.code.contains_key(&leader) {
+            continue;
+        }
+        // Collect the block's instructions.
+        let mut block = vec![];
+        let mut addr = leader;
+        let end = loop {
+            let Some(insn) = program.code.get(&addr) else {
+                break addr;
+            };
+            if addr != leader && program.leaders.contains(&addr) {
+                break addr;
+            }
+            block.push((addr, *insn));
+            addr += 2;
+            if ends_block(insn) {
+                break addr;
+            }
+        };
+
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "fn block_{leader:03x}(chip: &mut Interpreter) -> bool {{"
+        )
+        .unwrap();
+        if writes_memory {
+            writeln!(
+                out,
+                "    if !chip.code_matches({leader:#05x}, &{:?}) {{",
+                program.bytes(leader, end)
+            )
+            .unwrap();
+            writeln!(out, "        return false;").unwrap();
+            writeln!(out, "    }}").unwrap();
+        }
+        let mut falls_through = true;
+        for (addr, insn) in block {
+            writeln!(out, "    // {addr:#05x}: {insn:?}").unwrap();
+            match emit_inline(&insn) {
+                Some(code) => {
+                    if !code.is_empty() {
+                        writeln!(out, "    {{ {code} }}").unwrap();
+                    }
+                    writeln!(out, "    chip.retire();").unwrap();
+                }
+                None => {
+                    writeln!(out, "    chip.execute_at({addr:#05x}, {insn:?});").unwrap();
+                    falls_through = !ends_block(&insn);
+                }
+            }
+        }
+        if falls_through {
+            writeln!(out, "    chip.set_pc({end:#05x});").unwrap();
+        }
+        writeln!(out, "    true").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(code: &[u16]) -> Vec<u8> {
+        code.iter().flat_map(|insn| insn.to_be_bytes()).collect()
+    }
+
+    // The source of each generated block, by start address.
+    fn blocks(source: &str) -> BTreeMap<u16, &str> {
+        source
+            .split("\nfn block_")
+            .skip(1)
+            .map(|block| (u16::from_str_radix(&block[..3], 16).unwrap(), block))
+            .collect()
+    }
+
+    #[test]
+    fn splits_blocks_at_control_flow_and_draw() {
+        let rom = rom(&[
+            0x2208, // 0x200: call 0x208
+            0xD015, // 0x202: draw
+            0x3001, // 0x204: skip if V0 == 1
+            0x1200, // 0x206: jump 0x200
+            0x6001, // 0x208: V0 = 1
+            0x00EE, // 0x20A: return
+        ]);
+        let source = recompile(&rom, "test.ch8");
+        let blocks = blocks(&source);
+        assert_eq!(
+            blocks.keys().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x204, 0x206, 0x208]
+        );
+        for leader in blocks.keys() {
+            assert!(source.contains(&format!("{leader:#05x} => block_{leader:03x}(chip),")));
+        }
+        // Blocks ending in control flow or a draw leave the pc to the interpreter.
+        assert!(blocks[&0x202].contains("chip.execute_at(0x202, Draw(0, 1, 5));"));
+        assert!(!blocks[&0x202].contains("set_pc"));
+        assert!(blocks[&0x204].contains("chip.execute_at(0x204, SkipEqIm(0, 1));"));
+        assert!(!blocks[&0x204].contains("set_pc"));
+        // Register operations are inline, up to the return.
+        assert!(blocks[&0x208].contains("chip.v_mut()[0] = 0x01;"));
+        assert!(blocks[&0x208].contains("chip.execute_at(0x20a, Return);"));
+        assert!(!source.contains("code_matches"));
+    }
+
+    #[test]
+    fn guards_blocks_when_memory_is_written() {
+        let rom = rom(&[
+            0x6001, // 0x200: V0 = 1
+            0xF055, // 0x202: store V0
+            0x7001, // 0x204: V0 += 1
+            0x1204, // 0x206: jump 0x204
+        ]);
+        let source = recompile(&rom, "test.ch8");
+        let blocks = blocks(&source);
+        assert_eq!(blocks.keys().copied().collect::<Vec<_>>(), [0x200, 0x204]);
+        assert!(blocks[&0x200].contains("if !chip.code_matches(0x200, &[96, 1, 240, 85])"));
+        assert!(blocks[&0x200].contains("chip.execute_at(0x202, StoreRegs(0));"));
+        assert!(!blocks[&0x200].contains("set_pc"));
+        assert!(blocks[&0x204].contains("if !chip.code_matches(0x204, &[112, 1, 18, 4])"));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::chip8::constants::*;
+use crate::chip8::sound::{Pattern, Synth, SAMPLE_RATE};
+use crate::chip8::{Display, Platform};
+use crate::config::Settings;
+use crate::render::Renderer;
+use log::info;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Records gameplay, one frame per emulated 60Hz tick regardless of how fast the
+// emulator actually runs. Recordings to .png/.apng files are written as animated
+// PNGs, and to .gif files as animated GIFs. Recordings to .rgba/.raw files produce a
+// raw stream of RGBA frames, along with a WAV file of the beeper next to it, which can
+// be muxed with e.g.:
+//
+//   ffmpeg -f rawvideo -pix_fmt rgba -s 64x32 -r 60 -i game.rgba -i game.wav game.mp4
+//
+// where the size is that of the frames, which depends on the display filters and is
+// logged once the first frame is recorded.
+//
+// Frames all have the same size, while programs can switch between the SCHIP lores and
+// hires modes. Animations take the size of their largest frame. Raw streams start at the
+// hires size on the platforms that have a hires mode, or else at the size of the first
+// frame. Smaller frames are scaled up, and the recording stops if a frame does not fit.
+
+const AUDIO_SAMPLES_PER_FRAME: u32 = SAMPLE_RATE / 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Png,
+    Gif,
+}
+
+pub enum Recorder {
+    Animation {
+        path: PathBuf,
+        format: AnimationFormat,
+        renderer: Renderer,
+        // Distinct consecutive frames, along with how many ticks each was shown for.
+        // Identical frames are only merged without phosphor, which changes every tick.
+        frames: Vec<(Display, u16)>,
+        merge: bool,
+    },
+    Raw {
+        renderer: Renderer,
+        // Display size of the stream, once known.
+        size: Option<(usize, usize)>,
+        video: BufWriter<File>,
+        audio: hound::WavWriter<BufWriter<File>>,
+        synth: Synth,
+    },
+}
+
+// The animation format to record to a file in, or None for a raw stream, from the
+// file's extension.
+pub fn format(path: &Path) -> std::io::Result<Option<AnimationFormat>> {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("png" | "apng") => Ok(Some(AnimationFormat::Png)),
+        Some("gif") => Ok(Some(AnimationFormat::Gif)),
+        Some("rgba" | "raw") => Ok(None),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "unsupported format, use .png, .apng or .gif for an animation, or .rgba or .raw \
+             for a raw stream",
+        )),
+    }
+}
+
+impl Recorder {
+    pub fn start(path: &Path, settings: &Settings) -> std::io::Result<Recorder> {
+        let format = format(path)?;
+        let renderer = Renderer::from_settings(settings);
+        info!("Recording to {}", path.display());
+        if let Some(format) = format {
+            return Ok(Recorder::Animation {
+                path: path.to_owned(),
+                format,
+                renderer,
+                frames: vec![],
+                merge: settings.phosphor.is_none(),
+            });
+        }
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let audio = hound::WavWriter::create(path.with_extension("wav"), spec)
+            .map_err(std::io::Error::other)?;
+        let size = match settings.platform.unwrap_or_default() {
+            Platform::Chip8 => None,
+            Platform::Schip | Platform::XoChip => Some((SCHIP_HIRES_WIDTH, SCHIP_HIRES_HEIGHT)),
+        };
+        Ok(Recorder::Raw {
+            renderer,
+            size,
+            video: BufWriter::new(File::create(path)?),
+            audio,
+            synth: Synth::new(settings.tone()),
+        })
+    }
+
+    // Record a single emulated frame.
+    pub fn add_frame(
+        &mut self,
+        display: &Display,
+        beeping: bool,
+        pattern: Option<Pattern>,
+    ) -> std::io::Result<()> {
+        match self {
+            Recorder::Animation { frames, merge, .. } => match frames.last_mut() {
+                Some((last, ticks)) if *merge && last == display && *ticks < u16::MAX => {
+                    *ticks += 1
+                }
+                _ => frames.push((display.clone(), 1)),
+            },
+            Recorder::Raw {
+                renderer,
+                size,
+                video,
+                audio,
+                synth,
+            } => {
+                let first = size.is_none();
+                let (width, height) = *size.get_or_insert((display.width(), display.height()));
+                renderer.update(&*fit(display, width, height)?);
+                if first {
+                    let (width, height) = renderer.size();
+                    info!("Recording {}x{} frames", width, height);
+                }
+                video.write_all(renderer.frame())?;
+                synth.set_pattern(pattern);
+                for _ in 0..AUDIO_SAMPLES_PER_FRAME {
+                    let sample = synth.next_sample(beeping);
+                    audio
+                        .write_sample((sample * i16::MAX as f32) as i16)
+                        .map_err(std::io::Error::other)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> std::io::Result<()> {
+        match self {
+            Recorder::Animation {
+                path,
+                format: AnimationFormat::Png,
+                mut renderer,
+                frames,
+                ..
+            } => write_apng(&path, &mut renderer, &frames)?,
+            Recorder::Animation {
+                path,
+                format: AnimationFormat::Gif,
+                mut renderer,
+                frames,
+                ..
+            } => write_gif(&path, &mut renderer, &frames)?,
+            Recorder::Raw { video, audio, .. } => {
+                video.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                audio.finalize().map_err(std::io::Error::other)?;
+            }
+        }
+        info!("Recording finished");
+        Ok(())
+    }
+}
+
+fn write_apng(
+    path: &Path,
+    renderer: &mut Renderer,
+    frames: &[(Display, u16)],
+) -> std::io::Result<()> {
+    let Some(largest) = largest(frames) else {
+        return Ok(());
+    };
+    let (display_width, display_height) = (largest.width(), largest.height());
+    // The frame size depends on the display filters, so render one frame to find out.
+    renderer.update(largest);
+    let (width, height) = renderer.size();
+    info!("Recording {}x{} frames", width, height);
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+    for (display, ticks) in frames {
+        writer.set_frame_delay(*ticks, 60)?;
+        renderer.update(&*fit(display, display_width, display_height)?);
+        writer.write_image_data(renderer.frame())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+// GIF frame delays are in hundredths of a second, so they are rounded such that the
+// total length stays right.
+fn write_gif(
+    path: &Path,
+    renderer: &mut Renderer,
+    frames: &[(Display, u16)],
+) -> std::io::Result<()> {
+    let Some(largest) = largest(frames) else {
+        return Ok(());
+    };
+    let (display_width, display_height) = (largest.width(), largest.height());
+    renderer.update(largest);
+    let (width, height) = renderer.size();
+    info!("Recording {}x{} frames", width, height);
+    let (Ok(gif_width), Ok(gif_height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(std::io::Error::other(format!(
+            "{}x{} frames are too large for a GIF",
+            width, height
+        )));
+    };
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder =
+        gif::Encoder::new(file, gif_width, gif_height, &[]).map_err(std::io::Error::other)?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(std::io::Error::other)?;
+    let centis = |ticks: u64| (ticks * 100 + 30) / 60;
+    let mut elapsed = 0;
+    for (display, ticks) in frames {
+        renderer.update(&*fit(display, display_width, display_height)?);
+        let mut pixels = renderer.frame().to_vec();
+        let mut frame = gif::Frame::from_rgba_speed(gif_width, gif_height, &mut pixels, 10);
+        let end = elapsed + *ticks as u64;
+        frame.delay = (centis(end) - centis(elapsed)).min(u16::MAX as u64) as u16;
+        elapsed = end;
+        encoder.write_frame(&frame).map_err(std::io::Error::other)?;
+    }
+    Ok(())
+}
+
+// The widest display of an animation, whose size all frames are scaled to.
+fn largest(frames: &[(Display, u16)]) -> Option<&Display> {
+    frames
+        .iter()
+        .map(|(display, _)| display)
+        .max_by_key(|display| display.width())
+}
+
+// Scale a display up to the given size, if it is a whole multiple of it.
+fn fit(display: &Display, width: usize, height: usize) -> std::io::Result<Cow<'_, Display>> {
+    let factor = width / display.width();
+    if factor == 1 && height == display.height() {
+        Ok(Cow::Borrowed(display))
+    } else if factor > 1 && display.width() * factor == width && display.height() * factor == height
+    {
+        Ok(Cow::Owned(display.scaled(factor)))
+    } else {
+        Err(std::io::Error::other(format!(
+            "the display changed to {}x{}, which does not fit in {}x{} frames",
+            display.width(),
+            display.height(),
+            width,
+            height
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_follow_extension() {
+        let format = |path: &str| format(Path::new(path)).ok();
+        assert_eq!(format("clip.png"), Some(Some(AnimationFormat::Png)));
+        assert_eq!(format("clip.GIF"), Some(Some(AnimationFormat::Gif)));
+        assert_eq!(format("clip.rgba"), Some(None));
+        assert_eq!(format("clip.mp4"), None);
+        assert_eq!(format("clip"), None);
+    }
+
+    #[test]
+    fn gif_keeps_timing_and_scales_lores_frames() {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}.gif", std::process::id()));
+        let mut recorder = Recorder::start(&path, &Settings::default()).unwrap();
+        let lores = Display::new(CHIP8_WIDTH, CHIP8_HEIGHT);
+        let mut hires = Display::new(SCHIP_HIRES_WIDTH, SCHIP_HIRES_HEIGHT);
+        hires.draw_sprite(0, 0, 0, &[0xFF], true);
+        for _ in 0..3 {
+            recorder.add_frame(&lores, false, None).unwrap();
+        }
+        for _ in 0..2 {
+            recorder.add_frame(&hires, false, None).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        let size = (decoder.width() as usize, decoder.height() as usize);
+        let mut delays = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(size.0 * CHIP8_HEIGHT, size.1 * CHIP8_WIDTH);
+        assert!(size.0 >= SCHIP_HIRES_WIDTH);
+        // 3 and 2 ticks at 60Hz, in hundredths of a second.
+        assert_eq!(delays, [5, 3]);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::chip8::Display;
+use crate::config::Settings;
+use crate::filter::{self, Filter};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::str::FromStr;
+
+// Frontend side of the display pipeline: converts the interpreter's monochrome display
+// to the RGBA format expected by Pixels, applying the palette and display filters. This
+// runs once per emulated frame, rather than once per sprite.
+//
+// For accessibility, the display can be surrounded with a border, which lights up in
+// the foreground color while the program beeps.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color([u8; 3]);
+
+impl Color {
+    pub const fn rgb(hex: u32) -> Color {
+        Color([(hex >> 16) as u8, (hex >> 8) as u8, hex as u8])
+    }
+
+    fn rgba(&self) -> [u8; 4] {
+        [self.0[0], self.0[1], self.0[2], 0xff]
+    }
+}
+
+// Parse colors in the #rrggbb format (the # is optional).
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        match u32::from_str_radix(hex, 16) {
+            Ok(value) if hex.len() == 6 => Ok(Color::rgb(value)),
+            _ => Err(format!("Invalid color {s}, expected #rrggbb")),
+        }
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Named color schemes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    #[default]
+    Classic,
+    GreenPhosphor,
+    Amber,
+    Lcd,
+    HighContrast,
+    Octo,
+}
+
+// Colors for the four combinations of the two XO-CHIP bitplanes: background (no plane
+// set), fg (first plane), fg2 (second plane) and blend (both planes). Monochrome
+// programs, which never select the second plane, only use the first two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    colors: [Color; 4],
+}
+
+impl Palette {
+    pub const fn new(bg: u32, fg: u32, fg2: u32, blend: u32) -> Palette {
+        Palette {
+            colors: [
+                Color::rgb(bg),
+                Color::rgb(fg),
+                Color::rgb(fg2),
+                Color::rgb(blend),
+            ],
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Palette {
+        let theme = if settings.high_contrast == Some(true) {
+            Theme::HighContrast
+        } else {
+            settings.theme.unwrap_or_default()
+        };
+        let mut palette = Palette::from(theme);
+        let overrides = [settings.bg, settings.fg, settings.fg2, settings.blend];
+        for (color, new_color) in palette.colors.iter_mut().zip(overrides) {
+            *color = new_color.unwrap_or(*color);
+        }
+        palette
+    }
+
+    // RGBA color for a palette index, as returned by Display::color.
+    pub fn rgba(&self, index: usize) -> [u8; 4] {
+        self.colors[index].rgba()
+    }
+}
+
+impl From<Theme> for Palette {
+    fn from(theme: Theme) -> Palette {
+        match theme {
+            Theme::Classic => Palette::new(0x000000, 0xffffff, 0xaaaaaa, 0x555555),
+            Theme::GreenPhosphor => Palette::new(0x0a140a, 0x33ff66, 0x1a8033, 0x99ffb3),
+            Theme::Amber => Palette::new(0x140c00, 0xffb000, 0x805800, 0xffd580),
+            Theme::Lcd => Palette::new(0x9bbc0f, 0x0f380f, 0x306230, 0x8bac0f),
+            Theme::HighContrast => Palette::new(0x000000, 0xffff00, 0x00ffff, 0xffffff),
+            Theme::Octo => Palette::new(0x996600, 0xffcc00, 0xff6600, 0x662200),
+        }
+    }
+}
+
+pub struct Renderer {
+    palette: Palette,
+    phosphor: Option<Phosphor>,
+    filter: Filter,
+    // Brightness of the darkened scanlines, if enabled.
+    scanlines: Option<f32>,
+    // Border width in CHIP-8 pixels, and whether it flashes while beeping.
+    border: usize,
+    visual_beep: bool,
+    beeping: bool,
+    // Size of the frame, after filtering.
+    width: usize,
+    height: usize,
+    frame: Vec<u8>,
+    // Output of the last filter, swapped with frame afterwards.
+    scratch: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new(palette: Palette) -> Self {
+        Renderer {
+            palette,
+            phosphor: None,
+            filter: Filter::None,
+            scanlines: None,
+            border: 0,
+            visual_beep: false,
+            beeping: false,
+            width: 0,
+            height: 0,
+            frame: vec![],
+            scratch: vec![],
+        }
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        let high_contrast = settings.high_contrast == Some(true);
+        let visual_beep = settings.visual_beep == Some(true);
+        // Flashing needs a border, even if none was asked for.
+        let border = settings
+            .border
+            .unwrap_or(match (high_contrast, visual_beep) {
+                (true, _) => 4,
+                (false, true) => 1,
+                (false, false) => 0,
+            });
+        // Phosphor and scanlines reduce contrast.
+        let scanlines = settings.scanlines.filter(|_| !high_contrast);
+        let renderer = Renderer::new(Palette::from_settings(settings))
+            .with_filter(settings.filter.unwrap_or_default(), scanlines)
+            .with_border(border as usize, visual_beep);
+        match settings.phosphor {
+            Some(decay) if !high_contrast => renderer.with_phosphor(decay),
+            _ => renderer,
+        }
+    }
+
+    pub fn with_phosphor(mut self, decay: f32) -> Self {
+        self.phosphor = Some(Phosphor::new(decay));
+        self
+    }
+
+    pub fn with_filter(mut self, filter: Filter, scanlines: Option<f32>) -> Self {
+        self.filter = filter;
+        self.scanlines = scanlines;
+        self
+    }
+
+    pub fn with_border(mut self, border: usize, visual_beep: bool) -> Self {
+        self.border = border;
+        self.visual_beep = visual_beep;
+        self
+    }
+
+    // Whether the program is beeping, for the visual beep. Takes effect on the next
+    // frame.
+    pub fn set_beeping(&mut self, beeping: bool) {
+        self.beeping = beeping;
+    }
+
+    // Render a new emulated frame.
+    pub fn update(&mut self, display: &Display) {
+        self.width = display.width();
+        self.height = display.height();
+        self.frame.resize(self.width * self.height * 4, 0);
+        let colors = self.palette.colors.map(|color| color.rgba());
+        let rows = self.frame.chunks_exact_mut(self.width * 4);
+        for (y, row) in rows.enumerate() {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                pixel.copy_from_slice(&colors[display.color(x, y)]);
+            }
+        }
+        if let Some(phosphor) = self.phosphor.as_mut() {
+            phosphor.apply(&mut self.frame, display);
+        }
+        if self.filter != Filter::None {
+            self.filter
+                .apply(&self.frame, self.width, self.height, &mut self.scratch);
+            std::mem::swap(&mut self.frame, &mut self.scratch);
+            self.width *= self.filter.factor();
+            self.height *= self.filter.factor();
+        }
+        if let Some(brightness) = self.scanlines {
+            filter::scanlines(&self.frame, self.width, brightness, &mut self.scratch);
+            std::mem::swap(&mut self.frame, &mut self.scratch);
+            self.width *= 2;
+            self.height *= 2;
+        }
+        if self.border > 0 {
+            self.add_border();
+        }
+    }
+
+    // Surround the frame with the border, in a dim foreground color, or the full one
+    // while beeping.
+    fn add_border(&mut self) {
+        let [bg, fg, ..] = self.palette.colors.map(|color| color.rgba());
+        let color = if self.visual_beep && self.beeping {
+            fg
+        } else {
+            [0, 1, 2, 3].map(|c| ((bg[c] as u16 * 3 + fg[c] as u16) / 4) as u8)
+        };
+        // The border scales with the frame, in CHIP-8 pixels.
+        let scanlines = if self.scanlines.is_some() { 2 } else { 1 };
+        let border = self.border * self.filter.factor() * scanlines;
+        let width = self.width + 2 * border;
+        let height = self.height + 2 * border;
+        self.scratch.clear();
+        self.scratch
+            .extend(std::iter::repeat_n(color, width * border).flatten());
+        for row in self.frame.chunks_exact(self.width * 4) {
+            self.scratch
+                .extend(std::iter::repeat_n(color, border).flatten());
+            self.scratch.extend_from_slice(row);
+            self.scratch
+                .extend(std::iter::repeat_n(color, border).flatten());
+        }
+        self.scratch
+            .extend(std::iter::repeat_n(color, width * border).flatten());
+        std::mem::swap(&mut self.frame, &mut self.scratch);
+        self.width = width;
+        self.height = height;
+    }
+
+    // The last rendered frame, in RGBA format.
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    pub fn background(&self) -> [u8; 4] {
+        self.palette.colors[0].rgba()
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width as u32, self.height as u32)
+    }
+}
+
+// Simulates the slow decay of CRT phosphor: pixels light up immediately, but fade out
+// to the background over a few frames when turned off. This hides most of the flicker
+// caused by programs erasing and redrawing sprites with XOR.
+struct Phosphor {
+    // Fraction of the remaining brightness kept on each frame.
+    decay: f32,
+    levels: Vec<[f32; 3]>,
+}
+
+impl Phosphor {
+    fn new(decay: f32) -> Self {
+        Phosphor {
+            decay: decay.clamp(0.0, 1.0),
+            levels: vec![],
+        }
+    }
+
+    fn apply(&mut self, frame: &mut [u8], display: &Display) {
+        let width = display.width();
+        if self.levels.len() != width * display.height() {
+            // First frame, or the resolution changed.
+            self.levels = frame
+                .chunks_exact(4)
+                .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+                .collect();
+        }
+        for (i, (pixel, level)) in frame
+            .chunks_exact_mut(4)
+            .zip(self.levels.iter_mut())
+            .enumerate()
+        {
+            let lit = display.pixel(i % width, i / width);
+            for c in 0..3 {
+                let target = pixel[c] as f32;
+                level[c] = if lit {
+                    target
+                } else {
+                    target + (level[c] - target) * self.decay
+                };
+                pixel[c] = level[c].round() as u8;
+            }
+        }
+    }
+}
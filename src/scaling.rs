@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+
+// Fits the frame to the window. Pixels only scales by integer factors and letterboxes
+// the rest, so any other scaling is done on the CPU, into a buffer of the final size.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ScalingMode {
+    // Largest integer multiple that fits the window, letterboxed. All pixels have
+    // the same size.
+    Integer,
+    // As large as fits the window while keeping the aspect ratio, with some pixels
+    // one screen pixel larger than others.
+    Stretch,
+}
+
+pub struct Scaler {
+    mode: ScalingMode,
+    window_size: (u32, u32),
+    width: usize,
+    height: usize,
+    frame: Vec<u8>,
+}
+
+impl Scaler {
+    pub fn new(mode: ScalingMode) -> Self {
+        Scaler {
+            mode,
+            window_size: (0, 0),
+            width: 0,
+            height: 0,
+            frame: vec![],
+        }
+    }
+
+    pub fn mode(&self) -> ScalingMode {
+        self.mode
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            ScalingMode::Integer => ScalingMode::Stretch,
+            ScalingMode::Stretch => ScalingMode::Integer,
+        };
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.window_size = (width, height);
+    }
+
+    // The frame to hand to Pixels, and its size.
+    pub fn apply<'a>(&'a mut self, frame: &'a [u8], size: (u32, u32)) -> (&'a [u8], (u32, u32)) {
+        let (src_width, src_height) = (size.0 as usize, size.1 as usize);
+        let (window_width, window_height) = self.window_size;
+        let (window_width, window_height) = (window_width as usize, window_height as usize);
+        if self.mode == ScalingMode::Integer
+            || src_width == 0
+            || window_width < src_width
+            || window_height < src_height
+        {
+            return (frame, size);
+        }
+
+        // Scale along the axis that runs out of room first, and derive the other from
+        // the aspect ratio.
+        if window_width * src_height <= window_height * src_width {
+            self.width = window_width;
+            self.height = src_height * window_width / src_width;
+        } else {
+            self.height = window_height;
+            self.width = src_width * window_height / src_height;
+        }
+        self.frame.resize(self.width * self.height * 4, 0);
+        for (y, row) in self.frame.chunks_exact_mut(self.width * 4).enumerate() {
+            let src_y = y * src_height / self.height;
+            let src_row = &frame[src_y * src_width * 4..][..src_width * 4];
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let src_x = x * src_width / self.width;
+                pixel.copy_from_slice(&src_row[src_x * 4..][..4]);
+            }
+        }
+        (&self.frame, (self.width as u32, self.height as u32))
+    }
+}
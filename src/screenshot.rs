@@ -0,0 +1,108 @@
+use crate::chip8::{Display, Interpreter};
+use crate::config::{headless_settings, Settings};
+use crate::render::Palette;
+use clap::Args;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Args, Debug)]
+pub struct ScreenshotArgs {
+    #[arg(long)]
+    pub binary: String,
+    /// Number of cycles to run before taking the screenshot.
+    #[arg(long, default_value_t = 1000)]
+    cycles: u64,
+    /// Random number seed, so that the same binary always gives the same screenshot.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[arg(long, default_value = "screenshot.png")]
+    output: String,
+    /// Integer scaling factor.
+    #[arg(long, default_value_t = 1)]
+    scale: u32,
+    /// Config file with global and per-ROM settings.
+    #[arg(long, default_value = crate::config::DEFAULT_CONFIG_PATH)]
+    config: String,
+    /// ROM database to detect settings from, instead of the bundled one.
+    #[arg(long)]
+    database: Option<String>,
+    #[command(flatten)]
+    settings: Settings,
+}
+
+// Run a binary headless for a number of cycles and save the screen as a PNG.
+pub fn run(args: &ScreenshotArgs) -> std::io::Result<()> {
+    let rom = std::fs::read(&args.binary)?;
+    let settings = headless_settings(
+        &args.binary,
+        &rom,
+        &args.config,
+        args.database.as_deref(),
+        &args.settings,
+    )?;
+
+    let mut chip8 = Interpreter::new()
+        .with_platform(settings.platform.unwrap_or_default())
+        .with_quirks(settings.quirks.unwrap_or_default())
+        .with_hz(settings.hz())
+        .with_seed(args.seed)
+        .load_rom(&rom)?;
+    for _ in 0..args.cycles {
+        chip8.cycle();
+    }
+    let palette = Palette::from_settings(&settings);
+    save(
+        chip8.display(),
+        &palette,
+        args.scale,
+        Path::new(&args.output),
+    )?;
+    println!("Wrote {}", args.output);
+    Ok(())
+}
+
+// File name for a screenshot (or recording) started from the window, based on the ROM
+// name and the current time.
+pub fn file_name(binary: &str, extension: &str) -> String {
+    let stem = Path::new(binary)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "chip8".into());
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or_default();
+    format!("{stem}-{millis}.{extension}")
+}
+
+// Save the display at its native resolution in the palette's colors, without display
+// filters or the border, scaled up by an integer factor.
+pub fn save(display: &Display, palette: &Palette, scale: u32, path: &Path) -> std::io::Result<()> {
+    let (width, height) = (display.width(), display.height());
+    let scale = scale.max(1) as usize;
+    let row_len = width * scale * 4;
+    let mut data = Vec::with_capacity(row_len * height * scale);
+    for y in 0..height {
+        let mut scaled_row = Vec::with_capacity(row_len);
+        for x in 0..width {
+            let pixel = palette.rgba(display.color(x, y));
+            for _ in 0..scale {
+                scaled_row.extend_from_slice(&pixel);
+            }
+        }
+        for _ in 0..scale {
+            data.extend_from_slice(&scaled_row);
+        }
+    }
+
+    let file = File::create(path)?;
+    let (width, height) = ((width * scale) as u32, (height * scale) as u32);
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
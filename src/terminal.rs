@@ -0,0 +1,274 @@
+use crate::config::Settings;
+use crate::emulator::{Emulator, EmulatorCommand, EmulatorEvent};
+use crate::movie::MovieMode;
+use crate::render::Renderer;
+use clap::ValueEnum;
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, terminal};
+use log::info;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+use winit::event::VirtualKeyCode;
+
+// Frontend rendering the display in the terminal and reading the keypad from raw-mode
+// stdin, so the emulator can be used over SSH or in containers without a display
+// server. It drives the same emulator core as the winit frontend.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Glyphs {
+    // One character per 1x2 pixels, in full color.
+    HalfBlock,
+    // One character per 2x4 pixels, in the foreground color only.
+    Braille,
+}
+
+// Most terminals only report key presses (and auto-repeat), not releases. In that case
+// keys are released after not having been repeated for this long.
+const KEY_HOLD_TIMEOUT: Duration = Duration::from_millis(150);
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// Puts the terminal in raw mode on the alternate screen, and restores it when dropped.
+struct TerminalGuard {
+    key_releases: bool,
+}
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        let key_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if key_releases {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        Ok(TerminalGuard { key_releases })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.key_releases {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub fn run(
+    rom: Vec<u8>,
+    mut renderer: Renderer,
+    settings: &Settings,
+    glyphs: Glyphs,
+    movie: Option<MovieMode>,
+) -> io::Result<()> {
+    // Only keys that produce a character can be used in the terminal.
+    let keyboard_map: HashMap<char, u32> = settings
+        .keyboard_map()
+        .by_keycode()
+        .into_iter()
+        .filter_map(|(keycode, key)| Some((keycode_char(keycode)?, key)))
+        .collect();
+
+    let guard = TerminalGuard::new()?;
+    let (events, receiver) = mpsc::channel();
+    let mut emulator = Emulator::spawn(rom, settings, movie, move |event| {
+        events.send(event).is_ok()
+    });
+    // Keys currently held down, and when they were last pressed (or repeated).
+    let mut held: HashMap<u32, Instant> = HashMap::new();
+    let mut stdout = io::stdout();
+
+    'run: loop {
+        // Drawing can fall behind on a slow connection, so only the latest of the frames
+        // that are ready gets drawn, and events do not pile up.
+        let mut frame_ready = false;
+        let mut event = receiver.recv_timeout(INPUT_POLL_INTERVAL);
+        loop {
+            match event {
+                Ok(EmulatorEvent::FrameReady) => frame_ready = true,
+                Ok(EmulatorEvent::MovieFinished(_)) => (),
+                Ok(EmulatorEvent::Exited) | Err(RecvTimeoutError::Disconnected) => break 'run,
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+            event = match receiver.try_recv() {
+                Ok(event) => Ok(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+            };
+        }
+        if frame_ready {
+            renderer.set_beeping(emulator.status().beeping);
+            renderer.update(&emulator.display());
+            draw(&mut stdout, &renderer, glyphs)?;
+        }
+
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(KeyEvent { code, kind, .. }) = event::read()? else {
+                continue;
+            };
+            if code == KeyCode::Esc {
+                info!("Exiting");
+                emulator.stop();
+                drop(guard);
+                return Ok(());
+            }
+            let KeyCode::Char(c) = code else {
+                continue;
+            };
+            let Some(&key) = keyboard_map.get(&c.to_ascii_lowercase()) else {
+                continue;
+            };
+            match kind {
+                KeyEventKind::Press | KeyEventKind::Repeat => {
+                    if held.insert(key, Instant::now()).is_none() {
+                        emulator.send(EmulatorCommand::KeyDown(key));
+                    }
+                }
+                KeyEventKind::Release => {
+                    held.remove(&key);
+                    emulator.send(EmulatorCommand::KeyUp(key));
+                }
+            }
+        }
+
+        if !guard.key_releases {
+            held.retain(|&key, pressed| {
+                let expired = pressed.elapsed() > KEY_HOLD_TIMEOUT;
+                if expired {
+                    emulator.send(EmulatorCommand::KeyUp(key));
+                }
+                !expired
+            });
+        }
+    }
+    emulator.stop();
+    Ok(())
+}
+
+fn draw(stdout: &mut impl Write, renderer: &Renderer, glyphs: Glyphs) -> io::Result<()> {
+    let (width, height) = renderer.size();
+    let (width, height) = (width as usize, height as usize);
+    let frame = renderer.frame();
+    let pixel = |x: usize, y: usize| -> [u8; 4] {
+        let offset = (y * width + x) * 4;
+        frame[offset..offset + 4].try_into().unwrap()
+    };
+    let to_color = |rgba: [u8; 4]| Color::Rgb {
+        r: rgba[0],
+        g: rgba[1],
+        b: rgba[2],
+    };
+
+    // Colors are only sent when they change, to keep the output small over slow links.
+    let mut last_colors = None;
+    queue!(stdout, cursor::MoveTo(0, 0))?;
+    match glyphs {
+        Glyphs::HalfBlock => {
+            for y in (0..height).step_by(2) {
+                for x in 0..width {
+                    let top = pixel(x, y);
+                    let bottom = if y + 1 < height { pixel(x, y + 1) } else { top };
+                    if last_colors != Some((top, bottom)) {
+                        queue!(
+                            stdout,
+                            SetForegroundColor(to_color(top)),
+                            SetBackgroundColor(to_color(bottom))
+                        )?;
+                        last_colors = Some((top, bottom));
+                    }
+                    queue!(stdout, Print('▀'))?;
+                }
+                queue!(stdout, cursor::MoveToNextLine(1))?;
+            }
+        }
+        Glyphs::Braille => {
+            // Dots are lit wherever a pixel differs from the background color.
+            let background = renderer.background();
+            queue!(stdout, SetBackgroundColor(to_color(background)))?;
+            // Braille dot bit for each (x, y) offset within a 2x4 cell.
+            const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+            for y in (0..height).step_by(4) {
+                for x in (0..width).step_by(2) {
+                    let mut bits = 0;
+                    let mut color = background;
+                    for (dy, row) in DOTS.iter().enumerate() {
+                        for (dx, bit) in row.iter().enumerate() {
+                            if x + dx >= width || y + dy >= height {
+                                continue;
+                            }
+                            let rgba = pixel(x + dx, y + dy);
+                            if rgba != background {
+                                bits |= bit;
+                                color = rgba;
+                            }
+                        }
+                    }
+                    let glyph = char::from_u32(0x2800 + bits).unwrap_or(' ');
+                    if last_colors != Some((color, background)) {
+                        queue!(stdout, SetForegroundColor(to_color(color)))?;
+                        last_colors = Some((color, background));
+                    }
+                    queue!(stdout, Print(glyph))?;
+                }
+                queue!(stdout, cursor::MoveToNextLine(1))?;
+            }
+        }
+    }
+    stdout.flush()
+}
+
+// The character produced by a key, for the keys used in keyboard maps.
+fn keycode_char(keycode: VirtualKeyCode) -> Option<char> {
+    use VirtualKeyCode::*;
+    let c = match keycode {
+        Key0 => '0',
+        Key1 => '1',
+        Key2 => '2',
+        Key3 => '3',
+        Key4 => '4',
+        Key5 => '5',
+        Key6 => '6',
+        Key7 => '7',
+        Key8 => '8',
+        Key9 => '9',
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    };
+    Some(c)
+}
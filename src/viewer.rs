@@ -0,0 +1,307 @@
+use crate::chip8::constants::*;
+use crate::chip8::{Display, DrawInfo};
+use crate::emulator::Status;
+use crate::osd::{draw_text, LINE_HEIGHT};
+use pixels::{Pixels, SurfaceTexture};
+use winit::dpi::LogicalSize;
+use winit::event::VirtualKeyCode;
+use winit::event_loop::EventLoopWindowTarget;
+use winit::window::{Window, WindowBuilder, WindowId};
+
+// Debug window showing memory as a grid of sprites: 8xN sprites, or 16x16 sprites while
+// the SCHIP hires mode is active. The sprite at I is highlighted, and a copy of the
+// screen shows where the last Draw instruction landed, outlined in red if it collided.
+//
+// The grid follows I until it is scrolled with the arrow keys or PageUp/PageDown. Home
+// goes back to following I, and [ and ] change the height of 8 pixel wide sprites.
+
+const COLUMNS: usize = 16;
+const ROWS: usize = 8;
+const MARGIN: usize = 2;
+const WINDOW_SCALE: f64 = 3.0;
+const TEXT: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+const DIM_TEXT: [u8; 4] = [0x80, 0x80, 0x80, 0xff];
+const LIT: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+const UNLIT: [u8; 4] = [0x30, 0x30, 0x30, 0xff];
+const SELECTED: [u8; 4] = [0x70, 0x60, 0x00, 0xff];
+const NO_COLLISION: [u8; 4] = [0x00, 0xc0, 0x00, 0xff];
+const COLLISION: [u8; 4] = [0xff, 0x00, 0x00, 0xff];
+const BACKGROUND: [u8; 4] = [0x00, 0x00, 0x00, 0xff];
+
+pub struct Viewer {
+    // Declared before the window, so that the surface is dropped first.
+    pixels: Pixels,
+    window: Window,
+    grid: SpriteGrid,
+}
+
+impl Viewer {
+    pub fn open<T>(target: &EventLoopWindowTarget<T>) -> Result<Viewer, String> {
+        let mut grid = SpriteGrid::new();
+        grid.render();
+        let size = LogicalSize::new(
+            grid.width as f64 * WINDOW_SCALE,
+            grid.height as f64 * WINDOW_SCALE,
+        );
+        let window = WindowBuilder::new()
+            .with_title("Chip8 sprites")
+            .with_inner_size(size)
+            .build(target)
+            .map_err(|e| e.to_string())?;
+        let window_size = window.inner_size();
+        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
+        let pixels = Pixels::new(grid.width as u32, grid.height as u32, surface_texture)
+            .map_err(|e| e.to_string())?;
+        Ok(Viewer {
+            pixels,
+            window,
+            grid,
+        })
+    }
+
+    pub fn id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    // Show the state of the emulator as of the last frame.
+    pub fn update(&mut self, display: &Display, memory: &[u8], status: Status) {
+        self.grid.display.clone_from(display);
+        self.grid.memory.clear();
+        self.grid.memory.extend_from_slice(memory);
+        self.grid.status = status;
+        self.grid.render();
+        self.window.request_redraw();
+    }
+
+    pub fn handle_key(&mut self, key: VirtualKeyCode) {
+        self.grid.handle_key(key);
+        self.grid.render();
+        self.window.request_redraw();
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.pixels
+            .resize_surface(width, height)
+            .expect("Could not resize viewer window");
+        self.window.request_redraw();
+    }
+
+    pub fn redraw(&mut self) {
+        let (width, height) = (self.grid.width as u32, self.grid.height as u32);
+        let buffer = self.pixels.texture().size();
+        if (buffer.width, buffer.height) != (width, height) {
+            self.pixels
+                .resize_buffer(width, height)
+                .expect("Could not resize viewer buffer");
+        }
+        self.pixels.frame_mut().copy_from_slice(&self.grid.frame);
+        self.pixels.render().expect("Error while rendering viewer");
+    }
+}
+
+struct SpriteGrid {
+    // First address shown, or None to follow I.
+    start: Option<usize>,
+    sprite_height: usize,
+    display: Display,
+    memory: Vec<u8>,
+    status: Status,
+    width: usize,
+    height: usize,
+    frame: Vec<u8>,
+}
+
+impl SpriteGrid {
+    fn new() -> Self {
+        SpriteGrid {
+            start: None,
+            sprite_height: 8,
+            display: Display::new(CHIP8_WIDTH, CHIP8_HEIGHT),
+            memory: vec![],
+            status: Status::default(),
+            width: 0,
+            height: 0,
+            frame: vec![],
+        }
+    }
+
+    // Sprite size in pixels and bytes.
+    fn sprite_size(&self) -> (usize, usize, usize) {
+        if self.display.width() > CHIP8_WIDTH {
+            (16, 16, 32)
+        } else {
+            (8, self.sprite_height, self.sprite_height)
+        }
+    }
+
+    fn page_start(&self) -> usize {
+        let (_, _, bytes) = self.sprite_size();
+        let page = bytes * COLUMNS * ROWS;
+        self.start.unwrap_or_else(|| {
+            let i = self.status.registers.i as usize;
+            i - i % page
+        })
+    }
+
+    fn handle_key(&mut self, key: VirtualKeyCode) {
+        let (_, _, bytes) = self.sprite_size();
+        let start = self.page_start();
+        let offset: isize = match key {
+            VirtualKeyCode::Home => {
+                self.start = None;
+                return;
+            }
+            VirtualKeyCode::LBracket => {
+                self.sprite_height = (self.sprite_height - 1).max(1);
+                return;
+            }
+            VirtualKeyCode::RBracket => {
+                self.sprite_height = (self.sprite_height + 1).min(15);
+                return;
+            }
+            VirtualKeyCode::Left => -1,
+            VirtualKeyCode::Right => 1,
+            VirtualKeyCode::Up => -((bytes * COLUMNS) as isize),
+            VirtualKeyCode::Down => (bytes * COLUMNS) as isize,
+            VirtualKeyCode::PageUp => -((bytes * COLUMNS * ROWS) as isize),
+            VirtualKeyCode::PageDown => (bytes * COLUMNS * ROWS) as isize,
+            _ => return,
+        };
+        let last = self.memory.len().saturating_sub(1) as isize;
+        self.start = Some((start as isize + offset).clamp(0, last) as usize);
+    }
+
+    fn render(&mut self) {
+        let (sprite_width, sprite_height, bytes) = self.sprite_size();
+        let (cell_width, cell_height) = (sprite_width + 1, sprite_height + 1);
+        let screen_top = MARGIN + 2 * LINE_HEIGHT + 1;
+        let grid_top = screen_top + self.display.height() + 4;
+        self.width = (COLUMNS * cell_width)
+            .max(self.display.width() + 2)
+            .max(120)
+            + 2 * MARGIN;
+        self.height = grid_top + ROWS * cell_height + LINE_HEIGHT + MARGIN;
+        self.frame.clear();
+        self.frame
+            .extend(std::iter::repeat_n(BACKGROUND, self.width * self.height).flatten());
+
+        // Header
+        let start = self.page_start();
+        let end = (start + bytes * COLUMNS * ROWS)
+            .min(self.memory.len())
+            .saturating_sub(1);
+        let i = self.status.registers.i as usize;
+        let header = format!(
+            "{:03X}-{:03X} {}X{}  I {:03X}",
+            start, end, sprite_width, sprite_height, i
+        );
+        draw_text(&mut self.frame, self.width, MARGIN, MARGIN, &header, TEXT);
+        let draw = match self.status.last_draw {
+            Some(draw) => format!(
+                "DRAW {:03X} AT {},{} {}",
+                draw.addr,
+                draw.x,
+                draw.y,
+                if draw.collision { "HIT" } else { "NO HIT" }
+            ),
+            None => "NO DRAW YET".to_string(),
+        };
+        let y = MARGIN + LINE_HEIGHT;
+        draw_text(&mut self.frame, self.width, MARGIN, y, &draw, TEXT);
+
+        // Copy of the screen, framed, with the last sprite drawn outlined.
+        let left = MARGIN + 1;
+        self.outline(
+            left - 1,
+            screen_top - 1,
+            self.display.width() + 2,
+            self.display.height() + 2,
+            UNLIT,
+        );
+        for y in 0..self.display.height() {
+            for x in 0..self.display.width() {
+                let color = if self.display.pixel(x, y) {
+                    LIT
+                } else {
+                    BACKGROUND
+                };
+                self.set(left + x, screen_top + y, color);
+            }
+        }
+        // The status and display are published separately, so they may disagree for a
+        // frame about the display mode.
+        let on_screen =
+            |draw: &DrawInfo| draw.x < self.display.width() && draw.y < self.display.height();
+        if let Some(draw) = self.status.last_draw.filter(on_screen) {
+            let color = if draw.collision {
+                COLLISION
+            } else {
+                NO_COLLISION
+            };
+            // Clipped to the screen, rather than wrapped around.
+            let width = (draw.width + 2).min(self.display.width() + 1 - draw.x);
+            let height = (draw.height + 2).min(self.display.height() + 1 - draw.y);
+            self.outline(
+                left + draw.x - 1,
+                screen_top + draw.y - 1,
+                width,
+                height,
+                color,
+            );
+        }
+
+        // Sprite grid
+        for n in 0..COLUMNS * ROWS {
+            let addr = start + n * bytes;
+            if addr >= self.memory.len() {
+                break;
+            }
+            let selected = (addr..addr + bytes).contains(&i);
+            let cell_x = MARGIN + (n % COLUMNS) * cell_width;
+            let cell_y = grid_top + (n / COLUMNS) * cell_height;
+            for row in 0..sprite_height {
+                let line = match sprite_width {
+                    16 => u16::from_be_bytes([
+                        self.byte(addr + 2 * row),
+                        self.byte(addr + 2 * row + 1),
+                    ]),
+                    _ => (self.byte(addr + row) as u16) << 8,
+                };
+                for col in 0..sprite_width {
+                    let lit = line & (0x8000 >> col) != 0;
+                    let color = match (lit, selected) {
+                        (true, _) => LIT,
+                        (false, true) => SELECTED,
+                        (false, false) => UNLIT,
+                    };
+                    self.set(cell_x + col, cell_y + row, color);
+                }
+            }
+        }
+        let help = "ARROWS PGUP PGDN HOME [ ]";
+        let y = self.height - MARGIN - LINE_HEIGHT + 1;
+        draw_text(&mut self.frame, self.width, MARGIN, y, help, DIM_TEXT);
+    }
+
+    fn byte(&self, addr: usize) -> u8 {
+        self.memory.get(addr).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        if x < self.width && y < self.height {
+            let offset = (y * self.width + x) * 4;
+            self.frame[offset..offset + 4].copy_from_slice(&color);
+        }
+    }
+
+    fn outline(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 4]) {
+        for dx in 0..width {
+            self.set(x + dx, y, color);
+            self.set(x + dx, y + height - 1, color);
+        }
+        for dy in 0..height {
+            self.set(x, y + dy, color);
+            self.set(x + width - 1, y + dy, color);
+        }
+    }
+}